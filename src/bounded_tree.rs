@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use cidr::Cidr;
+use cidr_tree::{CidrTree, Entry};
+
+// A CidrTree holding at most a fixed number of entries. When it is full,
+// inserting a new CIDR evicts the entry least recently inserted or matched by
+// longest_match(), so the tree can serve as a cache in front of a slower store.
+pub struct BoundedCidrTree<T> {
+    // Each entry's data along with the tick it was last used at
+    tree: CidrTree<(T, u64)>,
+    // Entries by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, Cidr>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl<T> BoundedCidrTree<T> {
+    // Panics if capacity is zero
+    pub fn new(capacity: usize) -> BoundedCidrTree<T> {
+        assert!(capacity > 0, "BoundedCidrTree capacity must be non-zero");
+        BoundedCidrTree {
+            tree: CidrTree::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // Stores data at the given CIDR, marking it as the most recently used.
+    // Returns the entry that left the tree as a result, if any: the CIDR's
+    // previous data, or else the least recently used entry if the tree was
+    // full.
+    pub fn insert(&mut self, cidr: &Cidr, data: T) -> Option<(Cidr, T)> {
+        let key = cidr.canonicalize();
+        let tick = self.next_tick();
+        self.recency.insert(tick, key);
+        if let Some((old, old_tick)) = self.tree.insert(key, (data, tick)) {
+            self.recency.remove(&old_tick);
+            return Some((key, old));
+        }
+        if self.tree.len() > self.capacity {
+            return self.evict();
+        }
+        None
+    }
+
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        let (data, tick) = self.tree.remove(cidr)?;
+        self.recency.remove(&tick);
+        Some(data)
+    }
+
+    // Like CidrTree::longest_match(), marking the matched entry as the most
+    // recently used
+    pub fn longest_match(&mut self, cidr: &Cidr) -> Option<&T> {
+        let (key, _) = self.tree.longest_match_with_prefix(cidr)?;
+        let tick = self.next_tick();
+        match self.tree.entry(&key) {
+            Entry::Occupied(entry) => {
+                let used = entry.into_mut();
+                self.recency.remove(&used.1);
+                self.recency.insert(tick, key);
+                used.1 = tick;
+                Some(&used.0)
+            },
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    // Returns the data stored at exactly the given CIDR without marking it used
+    pub fn get_exact(&self, cidr: &Cidr) -> Option<&T> {
+        self.tree.get_exact(cidr).map(|(data, _)| data)
+    }
+
+    fn evict(&mut self) -> Option<(Cidr, T)> {
+        let (_, oldest) = self.recency.pop_first()?;
+        self.tree.remove(&oldest).map(|(data, _)| (oldest, data))
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_eviction() {
+    let mut t = BoundedCidrTree::<&str>::new(2);
+    let a = Cidr::from_str("10.0.0.0/8").unwrap();
+    let b = Cidr::from_str("10.1.0.0/16").unwrap();
+    let c = Cidr::from_str("192.168.0.0/16").unwrap();
+
+    assert!(t.insert(&a, "a").is_none());
+    assert!(t.insert(&b, "b").is_none());
+
+    // Matching a makes b the least recently used
+    assert!(t.longest_match(&Cidr::from_str("10.2.0.0").unwrap()) == Some(&"a"));
+    assert!(t.insert(&c, "c") == Some((b, "b")));
+    assert!(t.len() == 2);
+    assert!(t.get_exact(&b).is_none());
+
+    // Replacing data doesn't evict anything else
+    assert!(t.insert(&c, "c2") == Some((c, "c")));
+    assert!(t.insert(&b, "b") == Some((a, "a")));
+
+    assert!(t.remove(&c) == Some("c2"));
+    assert!(t.insert(&a, "a").is_none());
+    assert!(t.len() == 2);
+    assert!(t.longest_match(&Cidr::from_str("10.1.0.1").unwrap()) == Some(&"b"));
+}
+
+#[test]
+#[should_panic(expected = "BoundedCidrTree capacity must be non-zero")]
+fn test_zero_capacity() {
+    BoundedCidrTree::<&str>::new(0);
+}
+
+#[test]
+fn test_eviction_capacity_one() {
+    let mut t = BoundedCidrTree::<&str>::new(1);
+    let a = Cidr::from_str("10.0.0.0/8").unwrap();
+    let b = Cidr::from_str("10.1.0.0/16").unwrap();
+
+    // Each new CIDR evicts the only one stored, even one covering it
+    assert!(t.insert(&a, "a").is_none());
+    assert!(t.insert(&b, "b") == Some((a, "a")));
+    assert!(t.len() == 1 && t.get_exact(&a).is_none());
+    assert!(t.longest_match(&Cidr::from_str("10.2.0.0").unwrap()).is_none());
+
+    // Replacing the stored CIDR, by any form of its key, evicts nothing
+    assert!(t.insert(&Cidr::from_str("10.1.2.3/16").unwrap(), "b2") == Some((b, "b")));
+    assert!(t.len() == 1 && t.get_exact(&b) == Some(&"b2"));
+
+    // Removing frees the slot, so the next insert evicts nothing
+    assert!(t.remove(&b) == Some("b2"));
+    assert!(t.is_empty());
+    assert!(t.insert(&a, "a").is_none());
+    assert!(t.longest_match(&Cidr::from_str("10.1.0.1").unwrap()) == Some(&"a"));
+    assert!(t.insert(&b, "b") == Some((a, "a")));
+}
@@ -1,9 +1,16 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
 use std::net;
 use std::str::FromStr;
 use std::num;
 use prefix::Prefix;
+use special;
+#[cfg(feature = "rand")]
+use rand::Rng;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Cidr {
     pub prefix: Prefix,
     pub length: u8,
@@ -13,6 +20,28 @@ pub struct Cidr {
 pub enum CidrParseError {
     Prefix(net::AddrParseError),
     Length(num::ParseIntError),
+    // The length exceeded the number of bits in an address of its family
+    LengthOutOfRange(u8),
+    // Bits past the length were set; only returned by from_str_strict()
+    HostBits,
+    // The mask was missing, of the wrong family, or not contiguous; only
+    // returned by from_str_with_mask()
+    Mask,
+    // Not a valid in-addr.arpa or ip6.arpa name; only returned by
+    // from_ptr_name()
+    PtrName,
+}
+
+// How from_str_with_mask() reads the mask following the address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskFormat {
+    // A netmask, e.g. 255.255.255.0 for a /24
+    Netmask,
+    // A Cisco wildcard mask, e.g. 0.0.0.255 for a /24
+    Wildcard,
+    // A netmask if its first bit is set, otherwise a wildcard mask. An
+    // all-zero mask is read as the wildcard mask of a single address.
+    Auto,
 }
 
 impl From<net::AddrParseError> for CidrParseError {
@@ -27,31 +56,179 @@ impl From<num::ParseIntError> for CidrParseError {
     }
 }
 
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CidrParseError::Prefix(ref err) => write!(f, "invalid address: {}", err),
+            CidrParseError::Length(ref err) => write!(f, "invalid prefix length: {}", err),
+            CidrParseError::LengthOutOfRange(length) =>
+                write!(f, "prefix length {} is too long for the address family", length),
+            CidrParseError::HostBits => write!(f, "address has bits set past the prefix length"),
+            CidrParseError::Mask => write!(f, "invalid or non-contiguous mask"),
+            CidrParseError::PtrName => write!(f, "invalid reverse DNS name"),
+        }
+    }
+}
+
+impl Error for CidrParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CidrParseError::Prefix(ref err) => Some(err),
+            CidrParseError::Length(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Formats the CIDR as addr/len, with IPv6 addresses in their RFC 5952 form,
+// e.g. 2001:db8::/32. The output parses back to the same CIDR.
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.length)
+    }
+}
+
+// CIDRs sort the way CidrTree::iter() yields them: IPv4 before IPv6, then by
+// address, then shorter prefixes before the longer ones they contain
+impl Ord for Cidr {
+    fn cmp(&self, other: &Cidr) -> Ordering {
+        let key = |c: &Cidr| (c.prefix, c.length);
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for Cidr {
+    fn partial_cmp(&self, other: &Cidr) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// IPv6 addresses may carry a zone ID, as in fe80::1%eth0/64; it is discarded.
+// Use Cidr::from_str_with_zone() to keep it.
 impl FromStr for Cidr {
     type Err = CidrParseError;
 
     fn from_str(s: &str) -> Result<Cidr, CidrParseError> {
-        let parts = s.split("/").collect::<Vec<&str>>();
-        let mut length = 32;
-        if parts.len() > 1 {
-            length = try!(parts[1].parse::<u8>());
-        }
-        let prefix = try!(Prefix::from_str(parts[0]));
-        return Ok(Cidr {
-            prefix: prefix,
-            length: length,
-        })
+        Cidr::from_str_with_zone(s).map(|(cidr, _)| cidr)
+    }
+}
+
+// CidrTree methods taking a query accept anything convertible to a Cidr, so
+// callers holding an address don't have to build one by hand
+impl<'a> From<&'a Cidr> for Cidr {
+    fn from(cidr: &'a Cidr) -> Cidr {
+        *cidr
+    }
+}
+
+// A single address, as a full-length CIDR
+impl From<net::IpAddr> for Cidr {
+    fn from(ip: net::IpAddr) -> Cidr {
+        Cidr::host(ip)
+    }
+}
+
+impl From<net::Ipv4Addr> for Cidr {
+    fn from(ip: net::Ipv4Addr) -> Cidr {
+        Cidr::host_v4(ip)
+    }
+}
+
+impl From<net::Ipv6Addr> for Cidr {
+    fn from(ip: net::Ipv6Addr) -> Cidr {
+        Cidr::host_v6(ip)
+    }
+}
+
+// An address and a length, which must fit the address family
+impl TryFrom<(net::IpAddr, u8)> for Cidr {
+    type Error = CidrParseError;
+
+    fn try_from((ip, length): (net::IpAddr, u8)) -> Result<Cidr, CidrParseError> {
+        Cidr::with_length(Prefix::from_ip(ip), length)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Cidr {
+    type Error = CidrParseError;
+
+    fn try_from(s: &'a str) -> Result<Cidr, CidrParseError> {
+        Cidr::from_str(s)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CidrParseError;
+
+    fn try_from(s: String) -> Result<Cidr, CidrParseError> {
+        Cidr::from_str(&s)
     }
 }
 
 impl Cidr {
     pub fn new(prefix: Prefix, length: u8) -> Cidr {
         Cidr {
-            prefix: prefix,
-            length: length,
+            prefix,
+            length,
+        }
+    }
+
+    // Returns the full-length CIDR covering only the given address
+    pub fn host(ip: net::IpAddr) -> Cidr {
+        let prefix = Prefix::from_ip(ip);
+        Cidr::new(prefix, prefix.bits())
+    }
+
+    // Returns the /32 covering only the given IPv4 address
+    pub fn host_v4(ip: net::Ipv4Addr) -> Cidr {
+        Cidr::new(Prefix::from_ipv4(ip), 32)
+    }
+
+    // Returns the /128 covering only the given IPv6 address
+    pub fn host_v6(ip: net::Ipv6Addr) -> Cidr {
+        Cidr::new(Prefix::from_ipv6(ip), 128)
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self.prefix, Prefix::V4(_))
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.prefix, Prefix::V6(_))
+    }
+
+    // Like new(), but rejects a length longer than the address
+    fn with_length(prefix: Prefix, length: u8) -> Result<Cidr, CidrParseError> {
+        if length > prefix.bits() {
+            return Err(CidrParseError::LengthOutOfRange(length));
+        }
+        Ok(Cidr::new(prefix, length))
+    }
+
+    // Integer conversions. The integer is the address read as a big-endian
+    // number, e.g. 1.2.3.4 <-> 0x01020304, as databases commonly store it.
+    // Fails if the length is longer than the address.
+    pub fn from_u32(bits: u32, length: u8) -> Result<Cidr, CidrParseError> {
+        Cidr::with_length(Prefix::V4([0; 4]).with_int(bits as u128), length)
+    }
+
+    pub fn from_u128(bits: u128, length: u8) -> Result<Cidr, CidrParseError> {
+        Cidr::with_length(Prefix::V6([0; 16]).with_int(bits), length)
+    }
+
+    // Returns None for IPv6 CIDRs
+    pub fn to_u32(&self) -> Option<u32> {
+        match self.prefix {
+            Prefix::V4(_) => Some(self.prefix.to_int() as u32),
+            Prefix::V6(_) => None,
         }
     }
 
+    // IPv4 addresses occupy the low 32 bits
+    pub fn to_u128(&self) -> u128 {
+        self.prefix.to_int()
+    }
+
     pub fn next(&self) -> Cidr {
         Cidr::new(self.prefix.shift_left(1), self.length - 1)
     }
@@ -59,6 +236,564 @@ impl Cidr {
     pub fn msbit(&self) -> u8 {
         if self.length > 0 { self.prefix.msbit() } else { 0 }
     }
+
+    // Returns the /0 of the same address family
+    pub(crate) fn family_root(&self) -> Cidr {
+        Cidr::new(self.prefix.zeroed(), 0)
+    }
+
+    // Returns the first and last addresses in the CIDR as integers
+    pub(crate) fn span(&self) -> (u128, u128) {
+        let bits = self.prefix.bits();
+        let host_mask = match self.length >= bits {
+            true => 0,
+            false => (u128::MAX >> (128 - bits as u32)) >> self.length,
+        };
+        let first = self.prefix.to_int() & !host_mask;
+        (first, first | host_mask)
+    }
+
+    // Returns true if every address in other is also in this CIDR
+    pub fn contains(&self, other: &Cidr) -> bool {
+        let (first, last) = self.span();
+        let (other_first, other_last) = other.span();
+        self.prefix.bits() == other.prefix.bits() && first <= other_first && other_last <= last
+    }
+
+    // Returns true if the address is in this CIDR. Addresses of the other
+    // family never are.
+    pub fn contains_ip(&self, ip: net::IpAddr) -> bool {
+        self.contains(&Cidr::host(ip))
+    }
+
+    // Returns true if the CIDRs share any address, i.e. if either contains the
+    // other
+    pub fn overlaps(&self, other: &Cidr) -> bool {
+        self.contains(other) || other.contains(self)
+    }
+
+    // Returns the CIDR one bit shorter that contains this one, or None for a /0
+    pub fn supernet(&self) -> Option<Cidr> {
+        match self.length {
+            0 => None,
+            length => self.supernet_of_length(length - 1),
+        }
+    }
+
+    // Returns the CIDR of the given length that contains this one, with host
+    // bits cleared, or None if the length is longer than this CIDR's
+    pub fn supernet_of_length(&self, length: u8) -> Option<Cidr> {
+        match length <= self.length {
+            true => Some(Cidr::new(self.prefix, length).canonicalize()),
+            false => None,
+        }
+    }
+
+    // Truncates the CIDR to the given length with host bits cleared, e.g. for
+    // bucketing addresses by /24. Unlike supernet_of_length(), a CIDR already
+    // shorter than the length is returned as is (canonicalized).
+    pub fn apply_mask(&self, length: u8) -> Cidr {
+        Cidr::new(self.prefix, length.min(self.length)).canonicalize()
+    }
+
+    // Lazily iterates over the CIDRs of the given length that make up this one,
+    // in address order, e.g. the four /24s of a /22. Yields nothing if the
+    // length is shorter than this CIDR's or longer than its address family
+    // allows.
+    pub fn subnets(&self, length: u8) -> Subnets {
+        let (first, last) = self.span();
+        let valid = self.length <= length && length <= self.prefix.bits();
+        let (subnet_first, subnet_last) = Cidr::new(self.prefix, length).span();
+        Subnets {
+            prefix: self.prefix,
+            length,
+            next: if valid { Some(first) } else { None },
+            last,
+            host_mask: subnet_last - subnet_first,
+        }
+    }
+
+    // Returns the first address in the CIDR
+    pub fn first(&self) -> net::IpAddr {
+        self.prefix.with_int(self.span().0).to_ip_addr()
+    }
+
+    // Returns the last address in the CIDR
+    pub fn last(&self) -> net::IpAddr {
+        self.prefix.with_int(self.span().1).to_ip_addr()
+    }
+
+    // Returns the first and last addresses in the CIDR, the inverse of
+    // range_to_cidrs()
+    pub fn as_range(&self) -> (net::IpAddr, net::IpAddr) {
+        (self.first(), self.last())
+    }
+
+    // Returns the network address, i.e. the CIDR's address with host bits
+    // cleared; the same as first()
+    pub fn network(&self) -> net::IpAddr {
+        self.first()
+    }
+
+    // Returns the IPv4 broadcast address, i.e. the last address, or None for
+    // IPv6, which has no broadcast
+    pub fn broadcast(&self) -> Option<net::IpAddr> {
+        match self.is_ipv4() {
+            true => Some(self.last()),
+            false => None,
+        }
+    }
+
+    // Returns the number of addresses in the CIDR. As ::/0 holds one address
+    // more than a u128 can count, the result saturates at u128::MAX.
+    pub fn address_count(&self) -> u128 {
+        let (first, last) = self.span();
+        (last - first).saturating_add(1)
+    }
+
+    // Returns the netmask, e.g. 255.255.255.0 for a /24
+    pub fn netmask(&self) -> net::IpAddr {
+        let (first, last) = self.span();
+        self.prefix.with_int(!(last - first)).to_ip_addr()
+    }
+
+    // Returns the hostmask (Cisco wildcard mask), e.g. 0.0.0.255 for a /24
+    pub fn hostmask(&self) -> net::IpAddr {
+        let (first, last) = self.span();
+        self.prefix.with_int(last - first).to_ip_addr()
+    }
+
+    // Returns the index-th subnet of the given length, counting from zero in
+    // address order, e.g. the third /24 of 10.1.0.0/16 is 10.1.2.0/24. Returns
+    // None if there is no such subnet.
+    pub fn nth_subnet(&self, length: u8, index: u128) -> Option<Cidr> {
+        let bits = self.prefix.bits();
+        if length < self.length || length > bits {
+            return None;
+        }
+        // There are 2^(length - self.length) subnets
+        let extra = length - self.length;
+        if extra < 128 && index >> extra != 0 {
+            return None;
+        }
+        let offset = match bits - length {
+            128 => 0,
+            host_bits => index << host_bits,
+        };
+        Some(Cidr::new(self.prefix.with_int(self.span().0 + offset), length))
+    }
+
+    // Returns the CIDR of the same length n blocks after this one, or before it
+    // if n is negative, e.g. 10.0.3.0/24 for 10.0.1.0/24 offset by 2. Returns
+    // None if that would leave the address space.
+    pub fn offset(&self, n: i128) -> Option<Cidr> {
+        let (first, last) = self.span();
+        let host_mask = last - first;
+        let max = u128::MAX >> (128 - self.prefix.bits() as u32);
+        let distance = match n {
+            0 => 0,
+            _ => n.unsigned_abs().checked_mul(host_mask.checked_add(1)?)?,
+        };
+        let first = match n < 0 {
+            true => first.checked_sub(distance)?,
+            false => first.checked_add(distance)?,
+        };
+        if first > max - host_mask {
+            return None;
+        }
+        Some(Cidr::new(self.prefix.with_int(first), self.length))
+    }
+
+    // Returns an address chosen uniformly at random from the CIDR
+    #[cfg(feature = "rand")]
+    pub fn random_address<R: Rng + ?Sized>(&self, rng: &mut R) -> net::IpAddr {
+        let (first, last) = self.span();
+        self.prefix.with_int(rng.gen_range(first..=last)).to_ip_addr()
+    }
+
+    // Lazily iterates over every address in the CIDR, in order
+    pub fn hosts(&self) -> Hosts {
+        Hosts {
+            addrs: self.subnets(self.prefix.bits()),
+        }
+    }
+
+    // Like hosts(), but skips the network and broadcast addresses of IPv4
+    // CIDRs, which can't be assigned to hosts. /31s and /32s have no such
+    // addresses (RFC 3021), nor does IPv6.
+    pub fn usable_hosts(&self) -> Hosts {
+        let mut addrs = self.subnets(self.prefix.bits());
+        if self.is_ipv4() && self.length < 31 {
+            addrs.next = addrs.next.map(|first| first + 1);
+            addrs.last -= 1;
+        }
+        Hosts {
+            addrs,
+        }
+    }
+
+    // Returns the smallest set of CIDRs covering the same addresses as the
+    // given ones, sorted: CIDRs within others are dropped and pairs of adjacent
+    // halves are merged into their supernet, repeatedly
+    pub fn aggregate(cidrs: &[Cidr]) -> Vec<Cidr> {
+        let mut sorted = cidrs.iter().map(Cidr::canonicalize).collect::<Vec<_>>();
+        sorted.sort();
+        let mut merged = Vec::<Cidr>::with_capacity(sorted.len());
+        for cidr in sorted {
+            // Sorting puts each CIDR right after any kept CIDR containing it
+            if merged.last().is_some_and(|last| last.contains(&cidr)) {
+                continue;
+            }
+            let mut cidr = cidr;
+            while let Some(parent) = merged.last().and_then(|last| last.merge_with(&cidr)) {
+                merged.pop();
+                cidr = parent;
+            }
+            merged.push(cidr);
+        }
+        merged
+    }
+
+    // Returns the number of leading bits the CIDRs share, up to the shorter of
+    // their lengths. Panics if they are of different address families.
+    pub fn common_prefix_len(&self, other: &Cidr) -> u8 {
+        assert!(self.prefix.bits() == other.prefix.bits(), "CIDRs are of different address families");
+        self.prefix.common_prefix_len(&other.prefix).min(self.length).min(other.length)
+    }
+
+    // Returns the most specific CIDR containing both. Panics if they are of
+    // different address families.
+    pub fn common_supernet(&self, other: &Cidr) -> Cidr {
+        Cidr::new(self.prefix, self.common_prefix_len(other)).canonicalize()
+    }
+
+    // Returns true if the CIDRs are the two halves of the same supernet, e.g.
+    // 10.0.0.0/25 and 10.0.0.128/25
+    pub fn is_adjacent_sibling(&self, other: &Cidr) -> bool {
+        self.length == other.length &&
+            self.canonicalize() != other.canonicalize() &&
+            self.supernet().is_some_and(|parent| other.supernet() == Some(parent))
+    }
+
+    // Returns the supernet of the two CIDRs if they are adjacent siblings
+    pub fn merge_with(&self, other: &Cidr) -> Option<Cidr> {
+        match self.is_adjacent_sibling(other) {
+            true => self.supernet(),
+            false => None,
+        }
+    }
+
+    // Returns the fewest CIDRs covering every address in this one except those
+    // in the exceptions, in address order. E.g. 10.0.0.0/8 except 10.1.2.0/24
+    // leaves sixteen CIDRs from 10.0.0.0/16 to 10.128.0.0/9.
+    pub fn exclude(&self, exceptions: &[Cidr]) -> Vec<Cidr> {
+        let mut results = Vec::new();
+        let overlapping = exceptions.iter().filter(|e| e.overlaps(self)).cloned()
+            .collect::<Vec<_>>();
+        self.canonicalize().exclude_into(&overlapping, &mut results);
+        results
+    }
+
+    fn exclude_into(&self, exceptions: &[Cidr], results: &mut Vec<Cidr>) {
+        if exceptions.is_empty() {
+            results.push(*self);
+            return;
+        }
+        if exceptions.iter().any(|e| e.contains(self)) {
+            return;
+        }
+        // Some exception lies strictly within this CIDR, so split it in two
+        for bit in 0..2 {
+            let half = self.child(bit);
+            let within = exceptions.iter().filter(|e| half.contains(e)).cloned()
+                .collect::<Vec<_>>();
+            half.exclude_into(&within, results);
+        }
+    }
+
+    // Each of the following is true only if every address in the CIDR has the
+    // property. The ranges come from the special module.
+
+    // RFC 1918 IPv4 networks and IPv6 unique local addresses
+    pub fn is_private(&self) -> bool {
+        self.within_any(special::PRIVATE)
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.within_any(special::LOOPBACK)
+    }
+
+    pub fn is_link_local(&self) -> bool {
+        self.within_any(special::LINK_LOCAL)
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.within_any(special::MULTICAST)
+    }
+
+    pub fn is_documentation(&self) -> bool {
+        self.within_any(special::DOCUMENTATION)
+    }
+
+    // True if every address in the CIDR is globally reachable. Each address
+    // takes the reachability of the most specific IANA registry entry covering
+    // it, so e.g. 2001:20::/28 is global even though 2001::/23 is not.
+    // Addresses outside the registries are global.
+    pub fn is_global(&self) -> bool {
+        let cidr = self.canonicalize();
+        let registry = || special::IPV4_SPECIAL_PURPOSE.iter().chain(special::IPV6_SPECIAL_PURPOSE);
+        // Entries inside the CIDR may override the covering entry for part of
+        // it, so check each half separately until none are left
+        if registry().any(|entry| entry.cidr != cidr && cidr.contains(&entry.cidr)) {
+            return cidr.child(0).is_global() && cidr.child(1).is_global();
+        }
+        registry()
+            .filter(|entry| entry.cidr.contains(&cidr))
+            .max_by_key(|entry| entry.cidr.length)
+            .is_none_or(|entry| entry.globally_reachable)
+    }
+
+    // Returns the IPv4 CIDR tunneled by a 6to4 or Teredo CIDR. A 6to4 CIDR
+    // maps to the IPv4 network its bits 16-48 cover, e.g. 192.0.2.0/24 for
+    // 2002:c000:200::/40. Teredo only embeds the client address, so only a
+    // full-length Teredo CIDR maps to one.
+    pub fn embedded_ipv4(&self) -> Option<Cidr> {
+        if self.within_any(special::SIX_TO_FOUR) {
+            let ip = self.prefix.six_to_four_ipv4()?;
+            let length = self.length.min(48) - 16;
+            return Some(Cidr::new(Prefix::from_ipv4(ip), length).canonicalize());
+        }
+        match self.length {
+            128 => self.prefix.teredo_ipv4().map(Cidr::from),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn within_any(&self, networks: &[Cidr]) -> bool {
+        networks.iter().any(|network| network.contains(self))
+    }
+
+    // Returns the CIDR with every bit past its length cleared, e.g. 10.0.0.0/8
+    // for 10.1.2.3/8
+    pub fn canonicalize(&self) -> Cidr {
+        Cidr::new(self.prefix.with_int(self.span().0), self.length)
+    }
+
+    // Returns true if no bits past the CIDR's length are set
+    pub fn is_canonical(&self) -> bool {
+        self.canonicalize() == *self
+    }
+
+    // Like from_str(), but also returns the zone ID of an IPv6 address, as in
+    // fe80::1%eth0/64, instead of discarding it
+    pub fn from_str_with_zone(s: &str) -> Result<(Cidr, Option<String>), CidrParseError> {
+        let parts = s.splitn(2, '/').collect::<Vec<&str>>();
+        let (prefix, zone) = Prefix::from_str_with_zone(parts[0])?;
+        // A bare address is a single host: /32 for IPv4, /128 for IPv6
+        let mut length = prefix.bits();
+        if parts.len() > 1 {
+            length = parts[1].parse::<u8>()?;
+        }
+        if length > prefix.bits() {
+            return Err(CidrParseError::LengthOutOfRange(length));
+        }
+        Ok((Cidr::new(prefix, length), zone))
+    }
+
+    // Like from_str(), but rejects CIDRs with bits set past their length, such
+    // as 10.0.0.1/8, which usually indicate a typo
+    pub fn from_str_strict(s: &str) -> Result<Cidr, CidrParseError> {
+        let cidr = Cidr::from_str(s)?;
+        match cidr.is_canonical() {
+            true => Ok(cidr),
+            false => Err(CidrParseError::HostBits),
+        }
+    }
+
+    // Parses an address followed by whitespace and a mask, as found in device
+    // configurations and ACLs, e.g. "10.0.0.0 255.255.255.0" or, as a wildcard
+    // mask, "10.0.0.0 0.0.0.255"
+    pub fn from_str_with_mask(s: &str, format: MaskFormat) -> Result<Cidr, CidrParseError> {
+        let parts = s.split_whitespace().collect::<Vec<&str>>();
+        if parts.len() != 2 {
+            return Err(CidrParseError::Mask);
+        }
+        let prefix = Prefix::from_str(parts[0])?;
+        let mask = Prefix::from_str(parts[1])?;
+        let bits = prefix.bits();
+        if mask.bits() != bits {
+            return Err(CidrParseError::Mask);
+        }
+        // Align the mask to the top of a u128 so both families look alike
+        let mut mask = mask.to_int() << (128 - bits as u32);
+        let wildcard = match format {
+            MaskFormat::Netmask => false,
+            MaskFormat::Wildcard => true,
+            MaskFormat::Auto => mask >> 127 == 0,
+        };
+        if wildcard {
+            mask = !mask & (u128::MAX << (128 - bits as u32));
+        }
+        // A netmask must be a run of ones followed only by zeros
+        let length = mask.leading_ones();
+        if mask.checked_shl(length).unwrap_or(0) != 0 {
+            return Err(CidrParseError::Mask);
+        }
+        Ok(Cidr::new(prefix, length as u8))
+    }
+
+    // Returns the reverse DNS zone name containing the CIDR, e.g.
+    // 2.1.10.in-addr.arpa for 10.1.2.0/24. Reverse zones are delegated on octet
+    // (IPv4) or nibble (IPv6) boundaries, so a CIDR whose length falls between
+    // them gets the name of the enclosing zone; see to_ptr_names().
+    pub fn to_ptr_name(&self) -> String {
+        let (unit, suffix) = match self.prefix {
+            Prefix::V4(_) => (8, "in-addr.arpa"),
+            Prefix::V6(_) => (4, "ip6.arpa"),
+        };
+        let bits = self.prefix.bits() as u32;
+        let value = self.prefix.to_int();
+        let mut labels = (0..self.length as u32 / unit)
+            .map(|i| {
+                let label = (value >> (bits - unit * (i + 1))) & ((1 << unit) - 1);
+                match unit {
+                    8 => label.to_string(),
+                    _ => format!("{:x}", label),
+                }
+            })
+            .collect::<Vec<_>>();
+        labels.reverse();
+        labels.push(suffix.to_string());
+        labels.join(".")
+    }
+
+    // Returns the names of the reverse DNS zones that together make up exactly
+    // the CIDR, e.g. the four /24 zones of a /22
+    pub fn to_ptr_names(&self) -> Vec<String> {
+        let unit = match self.prefix {
+            Prefix::V4(_) => 8,
+            Prefix::V6(_) => 4,
+        };
+        let length = self.length.div_ceil(unit) * unit;
+        self.subnets(length).map(|zone| zone.to_ptr_name()).collect()
+    }
+
+    // Parses a reverse DNS name, such as 2.1.10.in-addr.arpa or a nibble-format
+    // ip6.arpa name, back into the CIDR it represents. A trailing dot is
+    // allowed and case is ignored.
+    pub fn from_ptr_name(name: &str) -> Result<Cidr, CidrParseError> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let (zero, unit, max_labels, labels) = if let Some(labels) = strip_arpa(&name, "in-addr.arpa") {
+            (Prefix::V4([0; 4]), 8, 4, labels)
+        } else if let Some(labels) = strip_arpa(&name, "ip6.arpa") {
+            (Prefix::V6([0; 16]), 4, 32, labels)
+        } else {
+            return Err(CidrParseError::PtrName);
+        };
+        let labels = match labels {
+            "" => Vec::new(),
+            labels => labels.split('.').collect::<Vec<_>>(),
+        };
+        if labels.len() > max_labels {
+            return Err(CidrParseError::PtrName);
+        }
+        let mut value = 0u128;
+        for label in labels.iter().rev() {
+            let digit = match unit {
+                8 if label.bytes().all(|b| b.is_ascii_digit()) => label.parse::<u8>().ok().map(u128::from),
+                4 if label.len() == 1 => u128::from_str_radix(label, 16).ok(),
+                _ => None,
+            };
+            value = (value << unit) | digit.ok_or(CidrParseError::PtrName)?;
+        }
+        let length = (labels.len() * unit) as u8;
+        let value = value.checked_shl((zero.bits() - length) as u32).unwrap_or(0);
+        Ok(Cidr::new(zero.with_int(value), length))
+    }
+
+    // Returns the CIDR one bit longer than this one, with the new bit set to
+    // `bit`. Used to rebuild keys while walking down the tree.
+    pub(crate) fn child(&self, bit: u8) -> Cidr {
+        let mut prefix = self.prefix;
+        prefix.set_bit(self.length as usize, bit);
+        Cidr::new(prefix, self.length + 1)
+    }
+}
+
+// Returns the labels in front of the given reverse DNS suffix, if the name ends
+// with it
+fn strip_arpa<'a>(name: &'a str, suffix: &str) -> Option<&'a str> {
+    if name == suffix {
+        return Some("");
+    }
+    name.strip_suffix(suffix).and_then(|labels| labels.strip_suffix('.'))
+}
+
+// Returns the fewest CIDRs that together cover exactly the addresses from start
+// to end, inclusive, in address order. Returns nothing if the addresses are of
+// different families or start comes after end.
+pub fn range_to_cidrs(start: net::IpAddr, end: net::IpAddr) -> Vec<Cidr> {
+    let (start, end) = (Prefix::from_ip(start), Prefix::from_ip(end));
+    let bits = start.bits();
+    let mut cidrs = Vec::new();
+    if bits != end.bits() || start.to_int() > end.to_int() {
+        return cidrs;
+    }
+    let host_mask = |n: u8| if n == 128 { u128::MAX } else { (1 << n) - 1 };
+    let (mut first, last) = (start.to_int(), end.to_int());
+    loop {
+        // The largest block aligned at first that doesn't run past last
+        let mut host_bits = (first.trailing_zeros() as u8).min(bits);
+        while host_mask(host_bits) > last - first {
+            host_bits -= 1;
+        }
+        cidrs.push(Cidr::new(start.with_int(first), bits - host_bits));
+        if last - first == host_mask(host_bits) {
+            return cidrs;
+        }
+        first += host_mask(host_bits) + 1;
+    }
+}
+
+// Iterator over the subnets of a CIDR, created by Cidr::subnets()
+pub struct Subnets {
+    prefix: Prefix,
+    length: u8,
+    // The first address of the next subnet, if any remain
+    next: Option<u128>,
+    // The last address of the CIDR being split
+    last: u128,
+    // One less than the number of addresses in each subnet
+    host_mask: u128,
+}
+
+impl Iterator for Subnets {
+    type Item = Cidr;
+
+    fn next(&mut self) -> Option<Cidr> {
+        let first = self.next?;
+        // Stop without overflowing once the last subnet is reached
+        self.next = match self.last - first > self.host_mask {
+            true => Some(first + self.host_mask + 1),
+            false => None,
+        };
+        Some(Cidr::new(self.prefix.with_int(first), self.length))
+    }
+}
+
+// Iterator over the addresses in a CIDR, created by Cidr::hosts() and
+// Cidr::usable_hosts()
+pub struct Hosts {
+    // The addresses as full-length subnets
+    addrs: Subnets,
+}
+
+impl Iterator for Hosts {
+    type Item = net::IpAddr;
+
+    fn next(&mut self) -> Option<net::IpAddr> {
+        self.addrs.next().map(|host| host.prefix.to_ip_addr())
+    }
 }
 
 #[test]
@@ -69,8 +804,201 @@ fn test_from_str() {
     assert!(Cidr::from_str("1.2.3.4/32").unwrap().length == 32);
     assert!(Cidr::from_str("1.2.3.4/0").unwrap().length == 0);
 
+    assert!(Cidr::from_str("1.2.3.4").unwrap().length == 32);
+    assert!(Cidr::from_str("2001:db8::1").unwrap().length == 128);
     assert!(Cidr::from_str("0::/0").unwrap().length == 0);
     assert!(Cidr::from_str("8000::/1").unwrap().length == 1);
+    assert!(Cidr::from_str("2001:db8::/32").unwrap().prefix ==
+            Prefix::from_ipv6("2001:db8::".parse().unwrap()));
+}
+
+#[test]
+fn test_from_str_zone() {
+    let c = Cidr::from_str("fe80::1%eth0/64").unwrap();
+    assert!(c == Cidr::from_str("fe80::1/64").unwrap());
+    assert!(Cidr::from_str("fe80::1%eth0").unwrap() == Cidr::from_str("fe80::1/128").unwrap());
+    assert!(Cidr::from_str_with_zone("fe80::1%eth0/64").unwrap() == (c, Some("eth0".to_string())));
+    assert!(Cidr::from_str_with_zone("fe80::1/64").unwrap() == (c, None));
+    assert!(Cidr::from_str("fe80::1%/64").is_err());
+    assert!(Cidr::from_str("10.0.0.1%eth0/8").is_err());
+}
+
+#[test]
+fn test_from_str_invalid() {
+    assert!(matches!(Cidr::from_str("1.2.3.4/33"), Err(CidrParseError::LengthOutOfRange(33))));
+    assert!(matches!(Cidr::from_str("::/129"), Err(CidrParseError::LengthOutOfRange(129))));
+    assert!(matches!(Cidr::from_str("1.2.3.4/200"), Err(CidrParseError::LengthOutOfRange(200))));
+    assert!(matches!(Cidr::from_str("1.2.3.4/"), Err(CidrParseError::Length(_))));
+    assert!(matches!(Cidr::from_str("1.2.3.4/8/8"), Err(CidrParseError::Length(_))));
+    assert!(matches!(Cidr::from_str("1.2.3/8"), Err(CidrParseError::Prefix(_))));
+    assert!(Cidr::from_str("::/128").is_ok());
+
+    let err = Cidr::from_str("1.2.3.4/33").unwrap_err();
+    assert!(err.to_string() == "prefix length 33 is too long for the address family");
+    assert!(err.source().is_none());
+    assert!(Cidr::from_str("1.2.3.4/x").unwrap_err().source().is_some());
+}
+
+#[test]
+fn test_display() {
+    for s in ["10.0.0.0/8", "1.2.3.4/32", "0.0.0.0/0", "2001:db8::/32", "::1/128",
+              "fe80::1:0:0:1/64", "::ffff:10.0.0.0/104"] {
+        let cidr = Cidr::from_str(s).unwrap();
+        assert!(cidr.to_string() == s);
+        assert!(Cidr::from_str(&cidr.to_string()).unwrap() == cidr);
+    }
+    assert!(Cidr::from_str("2001:0db8:0000::0001/64").unwrap().to_string() == "2001:db8::1/64");
+}
+
+#[test]
+fn test_ord() {
+    let sorted = ["0.0.0.0/0", "10.0.0.0/8", "10.0.0.0/16", "10.0.0.1/32", "10.1.0.0/16",
+                  "192.168.0.0/16", "::/0", "::1/128", "2001:db8::/32"]
+        .iter().map(|s| Cidr::from_str(s).unwrap()).collect::<Vec<_>>();
+    let mut shuffled = sorted.clone();
+    shuffled.reverse();
+    shuffled.swap(1, 4);
+    shuffled.sort();
+    assert!(shuffled == sorted);
+    assert!(sorted.binary_search(&Cidr::from_str("10.1.0.0/16").unwrap()) == Ok(4));
+    assert!(Cidr::from_str("255.255.255.255/32").unwrap() < Cidr::from_str("::/0").unwrap());
+}
+
+#[test]
+fn test_from_str_with_mask() {
+    let parse = |s: &str, format: MaskFormat| {
+        Cidr::from_str_with_mask(s, format).map(|c| c.to_string()).ok()
+    };
+    let some = |s: &str| Some(s.to_string());
+    assert!(parse("10.0.0.0 255.255.255.0", MaskFormat::Netmask) == some("10.0.0.0/24"));
+    assert!(parse("10.0.0.0  0.0.0.255", MaskFormat::Wildcard) == some("10.0.0.0/24"));
+    assert!(parse("10.0.0.0 255.255.255.0", MaskFormat::Auto) == some("10.0.0.0/24"));
+    assert!(parse("10.0.0.0 0.0.0.255", MaskFormat::Auto) == some("10.0.0.0/24"));
+    assert!(parse("10.0.0.1 0.0.0.0", MaskFormat::Auto) == some("10.0.0.1/32"));
+    assert!(parse("10.0.0.1 0.0.0.0", MaskFormat::Netmask) == some("10.0.0.1/0"));
+    assert!(parse("10.0.0.1 255.255.255.255", MaskFormat::Netmask) == some("10.0.0.1/32"));
+    assert!(parse("2001:db8:: ffff:ffff::", MaskFormat::Netmask) == some("2001:db8::/32"));
+    assert!(parse("2001:db8:: ::ffff:ffff:ffff:ffff:ffff:ffff", MaskFormat::Auto) ==
+            some("2001:db8::/32"));
+
+    assert!(parse("10.0.0.0 255.0.255.0", MaskFormat::Netmask).is_none());
+    assert!(parse("10.0.0.0 0.0.0.255", MaskFormat::Netmask).is_none());
+    assert!(parse("10.0.0.0 ffff::", MaskFormat::Netmask).is_none());
+    assert!(parse("10.0.0.0", MaskFormat::Auto).is_none());
+    assert!(parse("10.0.0.0/8 255.0.0.0", MaskFormat::Auto).is_none());
+}
+
+#[test]
+fn test_ptr_name() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.1.2.0/24").to_ptr_name() == "2.1.10.in-addr.arpa");
+    assert!(c("10.1.2.3/32").to_ptr_name() == "3.2.1.10.in-addr.arpa");
+    assert!(c("10.1.0.0/22").to_ptr_name() == "1.10.in-addr.arpa");
+    assert!(c("0.0.0.0/0").to_ptr_name() == "in-addr.arpa");
+    assert!(c("2001:db8::/32").to_ptr_name() == "8.b.d.0.1.0.0.2.ip6.arpa");
+    assert!(c("10.1.4.0/22").to_ptr_names() ==
+            vec!["4.1.10.in-addr.arpa", "5.1.10.in-addr.arpa",
+                 "6.1.10.in-addr.arpa", "7.1.10.in-addr.arpa"]);
+    assert!(c("2001:db8::/31").to_ptr_names().len() == 2);
+
+    for s in ["10.1.2.0/24", "10.1.2.3/32", "0.0.0.0/0", "2001:db8::/32", "::1/128", "::/0"] {
+        assert!(Cidr::from_ptr_name(&c(s).to_ptr_name()).unwrap() == c(s));
+    }
+    assert!(Cidr::from_ptr_name("2.1.10.IN-ADDR.ARPA.").unwrap() == c("10.1.2.0/24"));
+    for bad in ["", "arpa", "1.2.3.4.5.in-addr.arpa", "256.in-addr.arpa", "+1.in-addr.arpa",
+                "10.ip6.arpa", "g.ip6.arpa", "1..in-addr.arpa", "xin-addr.arpa"] {
+        assert!(Cidr::from_ptr_name(bad).is_err());
+    }
+}
+
+#[test]
+fn test_embedded_ipv4() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("2002:c000:201::/48").embedded_ipv4() == Some(c("192.0.2.1/32")));
+    assert!(c("2002:c000:201:1::/64").embedded_ipv4() == Some(c("192.0.2.1/32")));
+    assert!(c("2002:c000:2ff::/40").embedded_ipv4() == Some(c("192.0.2.0/24")));
+    assert!(c("2002::/16").embedded_ipv4() == Some(c("0.0.0.0/0")));
+    assert!(c("2002::/15").embedded_ipv4().is_none());
+
+    let teredo = c("2001:0:4136:e378:8000:63bf:3fff:fdd2/128");
+    assert!(teredo.embedded_ipv4() == Some(c("192.0.2.45/32")));
+    assert!(c("2001::/32").embedded_ipv4().is_none());
+    assert!(c("192.0.2.0/24").embedded_ipv4().is_none());
+}
+
+#[test]
+fn test_apply_mask() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.1.2.3/32").apply_mask(24) == c("10.1.2.0/24"));
+    assert!(c("10.1.2.3/32").apply_mask(0) == c("0.0.0.0/0"));
+    assert!(c("10.1.2.3/32").apply_mask(32) == c("10.1.2.3/32"));
+    assert!(c("10.1.0.0/16").apply_mask(24) == c("10.1.0.0/16"));
+    assert!(c("10.1.2.3/16").apply_mask(24) == c("10.1.0.0/16"));
+    assert!(c("2001:db8:1:2::1/128").apply_mask(48) == c("2001:db8:1::/48"));
+}
+
+#[test]
+fn test_hash() {
+    use std::collections::HashMap;
+
+    let mut seen = HashMap::new();
+    for s in ["10.0.0.0/8", "10.0.0.0/16", "::/0", "10.0.0.0/8"] {
+        *seen.entry(Cidr::from_str(s).unwrap()).or_insert(0) += 1;
+    }
+    assert!(seen.len() == 3);
+    assert!(seen[&Cidr::from_str("10.0.0.0/8").unwrap()] == 2);
+}
+
+#[test]
+fn test_family() {
+    assert!(Cidr::from_str("10.0.0.0/8").unwrap().is_ipv4());
+    assert!(!Cidr::from_str("10.0.0.0/8").unwrap().is_ipv6());
+    assert!(Cidr::from_str("::ffff:10.0.0.0/104").unwrap().is_ipv6());
+}
+
+#[test]
+fn test_int_conversions() {
+    let c = Cidr::from_u32(0x0a010200, 24).unwrap();
+    assert!(c == Cidr::from_str("10.1.2.0/24").unwrap());
+    assert!(c.to_u32() == Some(0x0a010200));
+    assert!(c.to_u128() == 0x0a010200);
+
+    let c = Cidr::from_u128(0x20010db8 << 96, 32).unwrap();
+    assert!(c == Cidr::from_str("2001:db8::/32").unwrap());
+    assert!(c.to_u32().is_none());
+    assert!(c.to_u128() == 0x20010db8 << 96);
+
+    assert!(Cidr::from_u32(0, 32).unwrap() == Cidr::from_str("0.0.0.0/32").unwrap());
+    assert!(matches!(Cidr::from_u32(0x0a010203, 33), Err(CidrParseError::LengthOutOfRange(33))));
+    assert!(Cidr::from_u128(1, 128).unwrap() == Cidr::from_str("::1/128").unwrap());
+    assert!(matches!(Cidr::from_u128(1, 129), Err(CidrParseError::LengthOutOfRange(129))));
+}
+
+#[test]
+fn test_host() {
+    let v6 = Cidr::host("::1".parse().unwrap());
+    assert!(Cidr::host("10.1.2.3".parse().unwrap()) == Cidr::from_str("10.1.2.3/32").unwrap());
+    assert!(v6.prefix == Prefix::from_str("::1").unwrap() && v6.length == 128);
+    assert!(Cidr::host_v4(net::Ipv4Addr::new(10, 1, 2, 3)) == Cidr::from_str("10.1.2.3").unwrap());
+    assert!(Cidr::host_v6(net::Ipv6Addr::LOCALHOST) == v6);
+    assert!(Cidr::from_str("::1").unwrap() == v6);
+}
+
+#[test]
+fn test_conversions() {
+    let ip = "10.1.2.3".parse::<net::IpAddr>().unwrap();
+    assert!(Cidr::from(ip) == Cidr::host(ip));
+    assert!(Cidr::try_from((ip, 8)).unwrap() == Cidr::from_str("10.1.2.3/8").unwrap());
+    assert!(Cidr::try_from((ip, 32)).unwrap() == Cidr::host(ip));
+    assert!(matches!(Cidr::try_from((ip, 33)), Err(CidrParseError::LengthOutOfRange(33))));
+    let v6 = "::1".parse::<net::IpAddr>().unwrap();
+    assert!(Cidr::try_from((v6, 128)).unwrap() == Cidr::host(v6));
+    assert!(matches!(Cidr::try_from((v6, 129)), Err(CidrParseError::LengthOutOfRange(129))));
+    assert!(Cidr::from(net::Ipv4Addr::new(10, 1, 2, 3)) == Cidr::from_str("10.1.2.3/32").unwrap());
+    assert!(Cidr::from(net::Ipv6Addr::LOCALHOST) == Cidr::from_str("::1/128").unwrap());
+    assert!(Cidr::try_from("10.0.0.0/8").unwrap() == Cidr::from_str("10.0.0.0/8").unwrap());
+    assert!(Cidr::try_from("10.0.0.0/x").is_err());
+    assert!(Cidr::try_from("::/0".to_string()).unwrap() == Cidr::from_str("::/0").unwrap());
 }
 
 #[test]
@@ -87,3 +1015,324 @@ fn test_msbit() {
     assert!(1 == Cidr::from_str("128.0.0.0").unwrap().msbit());
     assert!(0 == Cidr::from_str("128.0.0.0/0").unwrap().msbit());
 }
+
+#[test]
+fn test_span() {
+    assert!(Cidr::from_str("10.1.2.3/8").unwrap().span() == (0x0a000000, 0x0affffff));
+    assert!(Cidr::from_str("10.1.2.3").unwrap().span() == (0x0a010203, 0x0a010203));
+    assert!(Cidr::from_str("0.0.0.0/0").unwrap().span() == (0, 0xffffffff));
+    assert!(Cidr::from_str("::/0").unwrap().span() == (0, u128::MAX));
+    assert!(Cidr::from_str("::1/128").unwrap().span() == (1, 1));
+}
+
+#[test]
+fn test_contains() {
+    let c = Cidr::from_str("10.1.0.0/16").unwrap();
+    assert!(c.contains(&c));
+    assert!(c.contains(&Cidr::from_str("10.1.2.0/24").unwrap()));
+    assert!(!c.contains(&Cidr::from_str("10.0.0.0/8").unwrap()));
+    assert!(!c.contains(&Cidr::from_str("10.2.0.0/24").unwrap()));
+    assert!(!Cidr::from_str("::/0").unwrap().contains(&c));
+
+    assert!(c.contains_ip("10.1.255.255".parse().unwrap()));
+    assert!(!c.contains_ip("10.2.0.0".parse().unwrap()));
+    assert!(!c.contains_ip("::ffff:10.1.0.1".parse().unwrap()));
+}
+
+#[test]
+fn test_overlaps() {
+    let c = Cidr::from_str("10.1.0.0/16").unwrap();
+    assert!(c.overlaps(&c));
+    assert!(c.overlaps(&Cidr::from_str("10.0.0.0/8").unwrap()));
+    assert!(c.overlaps(&Cidr::from_str("10.1.2.3/32").unwrap()));
+    assert!(!c.overlaps(&Cidr::from_str("10.2.0.0/16").unwrap()));
+    assert!(!c.overlaps(&Cidr::from_str("::/0").unwrap()));
+}
+
+#[test]
+fn test_supernet() {
+    let c = Cidr::from_str("10.1.2.3/32").unwrap();
+    assert!(c.supernet() == Some(Cidr::from_str("10.1.2.2/31").unwrap()));
+    assert!(c.supernet_of_length(16) == Some(Cidr::from_str("10.1.0.0/16").unwrap()));
+    assert!(c.supernet_of_length(32) == Some(c));
+    assert!(c.supernet_of_length(0) == Some(Cidr::from_str("0.0.0.0/0").unwrap()));
+    assert!(Cidr::from_str("10.0.0.0/8").unwrap().supernet_of_length(9).is_none());
+    assert!(Cidr::from_str("::/0").unwrap().supernet().is_none());
+    assert!(Cidr::from_str("2001:db8::/32").unwrap().supernet() ==
+            Some(Cidr::from_str("2001:db8::/31").unwrap()));
+}
+
+#[test]
+fn test_subnets() {
+    let c = Cidr::from_str("10.0.4.0/22").unwrap();
+    assert!(c.subnets(24).map(|c| c.to_string()).collect::<Vec<_>>() ==
+            vec!["10.0.4.0/24", "10.0.5.0/24", "10.0.6.0/24", "10.0.7.0/24"]);
+    assert!(c.subnets(22).collect::<Vec<_>>() == vec![c]);
+    assert!(c.subnets(21).next().is_none());
+    assert!(c.subnets(33).next().is_none());
+    assert!(Cidr::from_str("255.255.255.254/31").unwrap().subnets(32).count() == 2);
+
+    let mut v6 = Cidr::from_str("2001:db8::/32").unwrap().subnets(48);
+    assert!(v6.next() == Some(Cidr::from_str("2001:db8::/48").unwrap()));
+    assert!(v6.next() == Some(Cidr::from_str("2001:db8:1::/48").unwrap()));
+    assert!(Cidr::from_str("::/0").unwrap().subnets(0).count() == 1);
+    assert!(Cidr::from_str("::/0").unwrap().subnets(1).count() == 2);
+    assert!(Cidr::from_str("::/0").unwrap().subnets(128).nth(5) ==
+            Some(Cidr::from_str("::5/128").unwrap()));
+}
+
+#[test]
+fn test_first_last() {
+    let ip = |s: &str| s.parse::<net::IpAddr>().unwrap();
+    let c = Cidr::from_str("10.1.2.3/16").unwrap();
+    assert!(c.first() == ip("10.1.0.0") && c.network() == ip("10.1.0.0"));
+    assert!(c.last() == ip("10.1.255.255") && c.broadcast() == Some(ip("10.1.255.255")));
+
+    let c = Cidr::from_str("2001:db8::1/32").unwrap();
+    assert!(c.network() == ip("2001:db8::"));
+    assert!(c.last() == ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"));
+    assert!(c.broadcast().is_none());
+}
+
+#[test]
+fn test_address_count() {
+    assert!(Cidr::from_str("10.0.0.0/8").unwrap().address_count() == 1 << 24);
+    assert!(Cidr::from_str("10.0.0.1/32").unwrap().address_count() == 1);
+    assert!(Cidr::from_str("0.0.0.0/0").unwrap().address_count() == 1 << 32);
+    assert!(Cidr::from_str("2001:db8::/32").unwrap().address_count() == 1 << 96);
+    assert!(Cidr::from_str("::/1").unwrap().address_count() == 1 << 127);
+    assert!(Cidr::from_str("::/0").unwrap().address_count() == u128::MAX);
+}
+
+#[test]
+fn test_range_to_cidrs() {
+    let range = |start: &str, end: &str| {
+        range_to_cidrs(start.parse().unwrap(), end.parse().unwrap())
+            .iter().map(|c| c.to_string()).collect::<Vec<_>>()
+    };
+    assert!(range("10.0.0.0", "10.0.0.255") == vec!["10.0.0.0/24"]);
+    assert!(range("10.0.0.1", "10.0.0.6") ==
+            vec!["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/31", "10.0.0.6/32"]);
+    assert!(range("10.0.0.255", "10.0.2.0") ==
+            vec!["10.0.0.255/32", "10.0.1.0/24", "10.0.2.0/32"]);
+    assert!(range("0.0.0.0", "255.255.255.255") == vec!["0.0.0.0/0"]);
+    assert!(range("::", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff") == vec!["::/0"]);
+    assert!(range("::1", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff").len() == 128);
+    assert!(range("10.0.0.5", "10.0.0.5") == vec!["10.0.0.5/32"]);
+    assert!(range("10.0.0.5", "10.0.0.4").is_empty());
+    assert!(range("10.0.0.5", "::1").is_empty());
+
+    for c in ["10.1.0.0/16", "10.1.2.3/32", "2001:db8::/32"] {
+        let c = Cidr::from_str(c).unwrap();
+        let (first, last) = c.as_range();
+        assert!(range_to_cidrs(first, last) == vec![c]);
+    }
+}
+
+#[test]
+fn test_common_supernet() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.1.2.0/24").common_prefix_len(&c("10.1.3.0/24")) == 23);
+    assert!(c("10.1.2.0/24").common_supernet(&c("10.1.3.0/24")) == c("10.1.2.0/23"));
+    assert!(c("10.1.2.0/24").common_supernet(&c("10.1.2.7/32")) == c("10.1.2.0/24"));
+    assert!(c("10.1.2.3/32").common_prefix_len(&c("10.1.2.3/32")) == 32);
+    assert!(c("10.0.0.0/8").common_supernet(&c("192.168.0.0/16")) == c("0.0.0.0/0"));
+    assert!(c("2001:db8::/48").common_supernet(&c("2001:db9::/48")) == c("2001:db8::/31"));
+}
+
+#[test]
+#[should_panic]
+fn test_common_supernet_families() {
+    Cidr::from_str("10.0.0.0/8").unwrap().common_supernet(&Cidr::from_str("::/8").unwrap());
+}
+
+#[test]
+fn test_merge_with() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.0.0.0/25").is_adjacent_sibling(&c("10.0.0.128/25")));
+    assert!(c("10.0.0.128/25").merge_with(&c("10.0.0.0/25")) == Some(c("10.0.0.0/24")));
+    assert!(c("2001:db8::/33").merge_with(&c("2001:db8:8000::/33")) == Some(c("2001:db8::/32")));
+
+    // Adjacent, but halves of different supernets
+    assert!(!c("10.0.1.0/24").is_adjacent_sibling(&c("10.0.2.0/24")));
+    assert!(c("10.0.0.0/25").merge_with(&c("10.0.0.0/25")).is_none());
+    assert!(c("10.0.0.0/25").merge_with(&c("10.0.0.128/26")).is_none());
+    assert!(c("0.0.0.0/0").merge_with(&c("::/0")).is_none());
+    assert!(c("0.0.0.0/1").merge_with(&c("8000::/1")).is_none());
+}
+
+#[test]
+fn test_aggregate() {
+    let cidrs = |v: &[&str]| v.iter().map(|s| Cidr::from_str(s).unwrap()).collect::<Vec<_>>();
+    let aggregate = |v: &[&str]| {
+        Cidr::aggregate(&cidrs(v)).iter().map(|c| c.to_string()).collect::<Vec<_>>()
+    };
+    assert!(aggregate(&[]).is_empty());
+    assert!(aggregate(&["10.0.0.0/25", "10.0.0.128/25"]) == vec!["10.0.0.0/24"]);
+    assert!(aggregate(&["10.0.0.3/32", "10.0.0.0/31", "10.0.0.2/32", "10.0.1.0/24"]) ==
+            vec!["10.0.0.0/30", "10.0.1.0/24"]);
+    assert!(aggregate(&["10.1.0.0/16", "10.0.0.0/8", "10.1.2.0/24", "10.0.0.0/8"]) ==
+            vec!["10.0.0.0/8"]);
+    assert!(aggregate(&["10.0.1.0/24", "10.0.2.0/24"]) == vec!["10.0.1.0/24", "10.0.2.0/24"]);
+    assert!(aggregate(&["::/1", "8000::/1", "0.0.0.0/1"]) == vec!["0.0.0.0/1", "::/0"]);
+    assert!(aggregate(&["10.0.0.1/24", "10.0.1.0/24"]) == vec!["10.0.0.0/23"]);
+}
+
+#[test]
+fn test_exclude() {
+    let cidrs = |v: &[&str]| v.iter().map(|s| Cidr::from_str(s).unwrap()).collect::<Vec<_>>();
+    let exclude = |c: &str, v: &[&str]| {
+        Cidr::from_str(c).unwrap().exclude(&cidrs(v)).iter().map(|c| c.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert!(exclude("10.0.0.0/24", &[]) == vec!["10.0.0.0/24"]);
+    assert!(exclude("10.0.0.0/24", &["10.1.0.0/24", "::/0"]) == vec!["10.0.0.0/24"]);
+    assert!(exclude("10.0.0.0/24", &["10.0.0.0/8"]).is_empty());
+    assert!(exclude("10.0.0.0/30", &["10.0.0.1/32"]) == vec!["10.0.0.0/32", "10.0.0.2/31"]);
+    assert!(exclude("10.0.0.0/24", &["10.0.0.0/26", "10.0.0.192/26"]) ==
+            vec!["10.0.0.64/26", "10.0.0.128/26"]);
+
+    let holes = Cidr::from_str("10.0.0.0/8").unwrap().exclude(&cidrs(&["10.1.2.0/24"]));
+    assert!(holes.len() == 16);
+    assert!(holes[0] == Cidr::from_str("10.0.0.0/16").unwrap());
+    assert!(holes[15] == Cidr::from_str("10.128.0.0/9").unwrap());
+    assert!(holes.iter().map(|c| c.address_count()).sum::<u128>() == (1 << 24) - (1 << 8));
+}
+
+#[test]
+fn test_masks() {
+    let ip = |s: &str| s.parse::<net::IpAddr>().unwrap();
+    let c = Cidr::from_str("10.1.2.0/24").unwrap();
+    assert!(c.netmask() == ip("255.255.255.0") && c.hostmask() == ip("0.0.0.255"));
+    let c = Cidr::from_str("10.0.0.0/0").unwrap();
+    assert!(c.netmask() == ip("0.0.0.0") && c.hostmask() == ip("255.255.255.255"));
+    let c = Cidr::from_str("2001:db8::/36").unwrap();
+    assert!(c.netmask() == ip("ffff:ffff:f000::"));
+    assert!(c.hostmask() == ip("0:0:fff:ffff:ffff:ffff:ffff:ffff"));
+    let c = Cidr::from_str("::1/128").unwrap();
+    assert!(c.netmask() == ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff") && c.hostmask() == ip("::"));
+}
+
+#[test]
+fn test_nth_subnet() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.1.0.0/16").nth_subnet(24, 2) == Some(c("10.1.2.0/24")));
+    assert!(c("10.1.0.0/16").nth_subnet(24, 255) == Some(c("10.1.255.0/24")));
+    assert!(c("10.1.0.0/16").nth_subnet(24, 256).is_none());
+    assert!(c("10.1.0.0/16").nth_subnet(16, 0) == Some(c("10.1.0.0/16")));
+    assert!(c("10.1.0.0/16").nth_subnet(15, 0).is_none());
+    assert!(c("10.1.0.0/16").nth_subnet(33, 0).is_none());
+    assert!(c("::/0").nth_subnet(0, 0) == Some(c("::/0")));
+    assert!(c("::/0").nth_subnet(128, u128::MAX) ==
+            Some(c("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128")));
+    assert!(c("::/0").nth_subnet(1, 1) == Some(c("8000::/1")));
+}
+
+#[test]
+fn test_offset() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.0.1.0/24").offset(2) == Some(c("10.0.3.0/24")));
+    assert!(c("10.0.1.0/24").offset(-1) == Some(c("10.0.0.0/24")));
+    assert!(c("10.0.1.0/24").offset(-2) == Some(c("9.255.255.0/24")));
+    assert!(c("0.0.1.0/24").offset(-2).is_none());
+    assert!(c("10.0.1.7/24").offset(0) == Some(c("10.0.1.0/24")));
+    assert!(c("255.255.255.0/24").offset(1).is_none());
+    assert!(c("0.0.0.0/0").offset(0) == Some(c("0.0.0.0/0")));
+    assert!(c("0.0.0.0/0").offset(1).is_none());
+    assert!(c("::/0").offset(1).is_none());
+    assert!(c("2001:db8::/32").offset(1) == Some(c("2001:db9::/32")));
+    assert!(c("::/1").offset(1) == Some(c("8000::/1")));
+}
+
+#[cfg(feature = "rand")]
+#[test]
+fn test_random_address() {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    let mut rng = StdRng::seed_from_u64(1);
+    for s in ["10.1.0.0/16", "10.1.2.3/32", "0.0.0.0/0", "2001:db8::/126", "::/0"] {
+        let c = Cidr::from_str(s).unwrap();
+        for _ in 0..100 {
+            assert!(c.contains_ip(c.random_address(&mut rng)));
+        }
+    }
+
+    // Every address of a small CIDR turns up
+    let c = Cidr::from_str("10.0.0.0/30").unwrap();
+    let mut seen = (0..200).map(|_| c.random_address(&mut rng)).collect::<Vec<_>>();
+    seen.sort();
+    seen.dedup();
+    assert!(seen == c.hosts().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_classification() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(c("10.1.0.0/16").is_private() && c("192.168.1.1/32").is_private());
+    assert!(c("fd00::/8").is_private());
+    assert!(!c("10.0.0.0/7").is_private() && !c("8.8.8.8/32").is_private());
+    assert!(c("127.0.0.1/32").is_loopback() && c("::1/128").is_loopback());
+    assert!(!c("::/127").is_loopback());
+    assert!(c("169.254.1.0/24").is_link_local() && c("fe80::1/128").is_link_local());
+    assert!(c("239.0.0.0/8").is_multicast() && c("ff02::1/128").is_multicast());
+    assert!(c("2001:db8:1::/48").is_documentation() && c("192.0.2.0/25").is_documentation());
+
+    assert!(c("8.8.8.0/24").is_global() && c("2606:4700::/32").is_global());
+    assert!(!c("10.0.0.1/32").is_global() && !c("fe80::/64").is_global());
+    // Partly private, so not entirely global
+    assert!(!c("8.0.0.0/4").is_global());
+    assert!(!c("::ffff:1.2.3.4/128").is_global());
+
+    // Globally reachable entries nested in non-global blocks, and vice versa
+    for s in ["192.0.0.9/32", "192.0.0.10/32", "2001:3::/32", "2001:4:112::/48", "2001:20::1/128"] {
+        assert!(c(s).is_global());
+    }
+    assert!(!c("192.88.99.1/32").is_global());
+    assert!(!c("192.0.0.0/24").is_global() && !c("192.0.0.8/31").is_global());
+    assert!(!c("2001::/23").is_global() && !c("2001:10::/27").is_global());
+    // Made up entirely of the global 2001:20::/28 and 2001:30::/28
+    assert!(c("2001:20::/27").is_global());
+    assert!(Prefix::from_str("2001:1::1").unwrap().is_global());
+}
+
+#[test]
+fn test_hosts() {
+    let ips = |c: &str, usable: bool| {
+        let c = Cidr::from_str(c).unwrap();
+        let hosts = if usable { c.usable_hosts() } else { c.hosts() };
+        hosts.map(|ip| ip.to_string()).collect::<Vec<_>>()
+    };
+    assert!(ips("10.0.0.0/30", false) == vec!["10.0.0.0", "10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    assert!(ips("10.0.0.0/30", true) == vec!["10.0.0.1", "10.0.0.2"]);
+    assert!(ips("10.0.0.0/31", true) == vec!["10.0.0.0", "10.0.0.1"]);
+    assert!(ips("10.0.0.7/32", true) == vec!["10.0.0.7"]);
+    assert!(ips("2001:db8::/127", true) == vec!["2001:db8::", "2001:db8::1"]);
+    assert!(Cidr::from_str("10.0.0.0/16").unwrap().usable_hosts().count() == (1 << 16) - 2);
+    assert!(Cidr::from_str("::/0").unwrap().hosts().nth(1) == Some("::1".parse().unwrap()));
+}
+
+#[test]
+fn test_canonicalize() {
+    assert!(Cidr::from_str("10.1.2.3/8").unwrap().canonicalize() == Cidr::from_str("10.0.0.0/8").unwrap());
+    assert!(Cidr::from_str("10.1.2.3/0").unwrap().canonicalize() == Cidr::from_str("0.0.0.0/0").unwrap());
+    assert!(Cidr::from_str("ffff::1/12").unwrap().canonicalize() == Cidr::from_str("fff0::/12").unwrap());
+    assert!(Cidr::from_str("10.0.0.0/8").unwrap().is_canonical());
+    assert!(!Cidr::from_str("10.0.0.1/8").unwrap().is_canonical());
+
+    assert!(Cidr::from_str_strict("10.0.0.0/8").is_ok());
+    assert!(Cidr::from_str_strict("1.2.3.4").is_ok());
+    assert!(matches!(Cidr::from_str_strict("10.0.0.1/8"), Err(CidrParseError::HostBits)));
+    assert!(matches!(Cidr::from_str_strict("10.0.0.1/x"), Err(CidrParseError::Length(_))));
+}
+
+#[test]
+fn test_child() {
+    let root = Cidr::from_str("10.0.0.0/8").unwrap().family_root();
+    assert!(root == Cidr::from_str("0.0.0.0/0").unwrap());
+    assert!(root.child(1) == Cidr::from_str("128.0.0.0/1").unwrap());
+    assert!(root.child(1).child(0).child(1) == Cidr::from_str("160.0.0.0/3").unwrap());
+
+    let root = Cidr::from_str("8000::/1").unwrap().family_root();
+    assert!(root.child(1) == Cidr::from_str("8000::/1").unwrap());
+}
@@ -1,150 +1,3219 @@
 use std::str::FromStr;
+use std::iter::FromIterator;
+use std::collections::VecDeque;
+#[cfg(test)]
+use std::convert::TryFrom;
+use std::iter::Peekable;
+use std::error::Error;
+use std::fmt;
 use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::mem;
+use std::net::IpAddr;
+use std::ops::{ControlFlow, RangeInclusive};
+use std::ptr::NonNull;
 use cidr::Cidr;
+use prefix::Prefix;
 
-#[derive(Debug)]
-pub struct CidrTree<T> where T: Debug {
-    zero: Option<Box<CidrTree<T>>>,
-    one: Option<Box<CidrTree<T>>>,
+#[derive(Clone)]
+pub struct CidrTree<T> {
+    // IPv4 and IPv6 prefixes live in separate tries so that each entry's
+    // address family can be recovered from where it is stored
+    v4: Node<T>,
+    v6: Node<T>,
+    // Number of CIDRs with data stored in the tree
+    len: usize,
+    // Which matches lookup() returns
+    match_policy: MatchPolicy,
+}
+
+// A node of the binary trie; each level down consumes one bit of the prefix
+#[derive(Clone)]
+struct Node<T> {
+    zero: Option<Box<Node<T>>>,
+    one: Option<Box<Node<T>>>,
     data: Option<T>,
 }
 
-impl<T> CidrTree<T> where T: Debug {
+// Order in which walk() visits nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    // Each node before the nodes below it
+    PreOrder,
+    // Each node after the nodes below it
+    PostOrder,
+}
+
+// The differences between two trees, as produced by CidrTree::diff(). Each list
+// is in the same order as CidrTree::iter().
+#[derive(Debug, PartialEq)]
+pub struct Changeset<T> {
+    // Entries only present in the other tree
+    pub added: Vec<(Cidr, T)>,
+    // Entries only present in this tree, with the data they had
+    pub removed: Vec<(Cidr, T)>,
+    // Entries present in both trees with different data, with the other tree's
+    // data
+    pub changed: Vec<(Cidr, T)>,
+}
+
+impl<T> Changeset<T> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// Structural statistics about a tree, as returned by CidrTree::stats(). Both
+// address families' root nodes are counted, even when empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    // Depth of the deepest node, i.e. the longest prefix length in the tree
+    pub max_depth: u8,
+    pub node_count: usize,
+    // Nodes holding data; always equal to len()
+    pub data_node_count: usize,
+    // Nodes by number of children
+    pub leaf_count: usize,
+    pub one_child_count: usize,
+    pub two_children_count: usize,
+}
+
+// Which of the stored CIDRs covering a query CidrTree::lookup() returns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchPolicy {
+    // Only the least specific, like a firewall where the first rule wins
+    FirstMatch,
+    // Only the most specific, like a router
+    LongestMatch,
+    // All of them, from least to most specific, as get() does
+    #[default]
+    AllMatches,
+}
+
+// What insert_with_policy() does when the CIDR already has data
+#[derive(Debug)]
+pub enum InsertPolicy<T> {
+    // Replace the existing data, as insert() does
+    Replace,
+    // Leave the existing data alone and discard the new data
+    KeepExisting,
+    // Fail with an OccupiedError, as try_insert() does
+    Error,
+    // Store the result of calling the function with the CIDR, the existing
+    // data and the new data
+    Merge(fn(&Cidr, T, T) -> T),
+}
+
+impl<T> Clone for InsertPolicy<T> {
+    fn clone(&self) -> InsertPolicy<T> {
+        *self
+    }
+}
+
+impl<T> Copy for InsertPolicy<T> {}
+
+// Returned by try_insert() when the CIDR already has data. The rejected value is
+// handed back to the caller.
+#[derive(Debug)]
+pub struct OccupiedError<T> {
+    pub cidr: Cidr,
+    pub value: T,
+}
+
+impl<T> fmt::Display for OccupiedError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CIDR {} already has data", self.cidr)
+    }
+}
+
+impl<T> Error for OccupiedError<T> where T: Debug {}
+
+// A broken structural invariant, as reported by CidrTree::check_invariants()
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvariantError {
+    // A node other than a family root that neither holds data nor leads to any
+    EmptyLeaf(Cidr),
+    // A node at the full length of its address family that still has children
+    TooDeep(Cidr),
+    // len() disagrees with the number of nodes holding data
+    LenMismatch { len: usize, counted: usize },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvariantError::EmptyLeaf(cidr) =>
+                write!(f, "empty leaf node at {}", cidr),
+            InvariantError::TooDeep(cidr) =>
+                write!(f, "node at {} has children past the address length", cidr),
+            InvariantError::LenMismatch { len, counted } =>
+                write!(f, "len is {} but {} nodes hold data", len, counted),
+        }
+    }
+}
+
+impl Error for InvariantError {}
+
+// Trees are equal when they hold the same CIDRs with equal data, however they
+// were built
+impl<T> PartialEq for CidrTree<T> where T: PartialEq {
+    fn eq(&self, other: &CidrTree<T>) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+// Shows the entries as a map from CIDR to data, e.g. {10.0.0.0/8: "a"}
+impl<T> Debug for CidrTree<T> where T: Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (cidr, d) in self.iter() {
+            map.entry(&format_args!("{}", cidr), d);
+        }
+        map.finish()
+    }
+}
+
+// Shows one "cidr -> data" line per entry, in prefix order. The alternate form
+// ({:#}) indents each entry under the stored CIDRs that cover it.
+impl<T> fmt::Display for CidrTree<T> where T: fmt::Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nested = f.alternate();
+        self.v4.fmt_entries(Cidr::new(Prefix::V4([0; 4]), 0), 0, nested, f)?;
+        self.v6.fmt_entries(Cidr::new(Prefix::V6([0; 16]), 0), 0, nested, f)
+    }
+}
+
+impl<T> Default for CidrTree<T> {
+    fn default() -> CidrTree<T> {
+        CidrTree::new()
+    }
+}
+
+impl<T> CidrTree<T> {
     pub fn new() -> CidrTree<T> {
         CidrTree {
-            zero: None,
-            one: None,
-            data: None,
+            v4: Node::new(),
+            v6: Node::new(),
+            len: 0,
+            match_policy: MatchPolicy::default(),
         }
     }
 
-    pub fn new_with_data(data: T) -> CidrTree<T> {
+    // Creates an empty tree whose lookup() follows the given policy
+    pub fn with_match_policy(match_policy: MatchPolicy) -> CidrTree<T> {
         CidrTree {
-            zero: None,
-            one: None,
-            data: Some(data),
+            match_policy,
+            ..CidrTree::new()
         }
     }
 
-    // Returns a vector of all the data that applies the queried CIDR
-    pub fn get(&self, cidr: &Cidr) -> Vec<&T> {
-        let mut results = Vec::<&T>::new();
+    // Creates a tree whose data applies to every address of either family
+    pub fn new_with_data(data: T) -> CidrTree<T> where T: Clone {
+        CidrTree {
+            v4: Node::new_with_data(data.clone()),
+            v6: Node::new_with_data(data),
+            len: 2,
+            match_policy: MatchPolicy::default(),
+        }
+    }
 
-        // I might have something to contribute
-        if let Some(ref d) = self.data {
-            results.push(d);
+    // Builds a tree in a single pass from entries sorted as iter() yields them:
+    // IPv4 before IPv6, then by address, then by length. Every node is created
+    // once rather than each entry being inserted from the root. Input that
+    // isn't sorted still loads correctly, only without the speedup. Later
+    // entries for the same CIDR replace earlier ones, as with insert().
+    pub fn from_sorted<I>(iter: I) -> CidrTree<T> where I: IntoIterator<Item = (Cidr, T)> {
+        let mut tree = CidrTree::new();
+        let mut iter = iter.into_iter().peekable();
+        while let Some(&(cidr, _)) = iter.peek() {
+            if tree.root(&cidr).is_prunable() {
+                let root = cidr.family_root();
+                let node = Node::from_sorted(root, &mut iter, &mut tree.len);
+                *tree.root_mut(&root) = node;
+            } else if let Some((cidr, data)) = iter.next() {
+                tree.insert(cidr, data);
+            }
         }
-        let next_cidr = cidr.next();
-        match cidr.msbit() {
-            0 => {
-                match self.zero {
-                    Some(ref child) => {
-                        results.extend(child.get(&next_cidr));
-                    },
-                    None => {}
-                }
-            },
-            _ => {
-                match self.one {
-                    Some(ref child) => {
-                        results.extend(child.get(&next_cidr));
-                    },
-                    None => {}
-                }
-            },
-        };
+        tree
+    }
+
+    // Returns a vector of all the data that applies the queried CIDR. Like
+    // insert(), has_exact() and covers(), it takes anything that converts to a
+    // Cidr without failing; strings and (IpAddr, length) pairs go through
+    // Cidr::try_from() first, so a bad key is an error for the caller to handle
+    // rather than a panic in here.
+    pub fn get<C: Into<Cidr>>(&self, cidr: C) -> Vec<&T> {
+        let cidr = cidr.into();
+        self.root(&cidr).get(&cidr)
+    }
+
+    // Returns the data of the stored CIDRs covering the query that the tree's
+    // match policy selects
+    pub fn lookup(&self, cidr: &Cidr) -> Vec<&T> {
+        match self.match_policy {
+            MatchPolicy::FirstMatch => self.shortest_match(cidr).into_iter().collect(),
+            MatchPolicy::LongestMatch => self.longest_match(cidr).into_iter().collect(),
+            MatchPolicy::AllMatches => self.get(cidr),
+        }
+    }
+
+    pub fn match_policy(&self) -> MatchPolicy {
+        self.match_policy
+    }
+
+    pub fn set_match_policy(&mut self, match_policy: MatchPolicy) {
+        self.match_policy = match_policy;
+    }
+
+    // Returns the data of the most specific stored CIDR covering the query
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<&T> {
+        self.root(cidr).longest_match(cidr)
+    }
+
+    // Returns the data stored at exactly the given CIDR, ignoring any covering
+    // entries
+    pub fn get_exact(&self, cidr: &Cidr) -> Option<&T> {
+        self.root(cidr).find(cidr).and_then(|node| node.data.as_ref())
+    }
+
+    // Returns true if data is stored at exactly the given CIDR
+    pub fn has_exact<C: Into<Cidr>>(&self, cidr: C) -> bool {
+        self.get_exact(&cidr.into()).is_some()
+    }
+
+    // Returns true if any stored CIDR covers the given one
+    pub fn covers<C: Into<Cidr>>(&self, cidr: C) -> bool {
+        self.ancestors(&cidr.into()).next().is_some()
+    }
+
+    // Returns true if any stored CIDR covers the given address
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.covers(Cidr::host(ip))
+    }
+
+    // Like lookup(), for a single address such as a socket's peer address
+    pub fn lookup_ip(&self, ip: IpAddr) -> Vec<&T> {
+        self.lookup(&Cidr::host(ip))
+    }
+
+    // Returns true if any stored CIDR overlaps the given one, i.e. either covers
+    // it or falls within it
+    pub fn overlaps(&self, cidr: &Cidr) -> bool {
+        self.covers(cidr) || self.covered_by(cidr).next().is_some()
+    }
+
+    // Like get(), but also returns the stored CIDR each piece of data belongs to,
+    // from least to most specific
+    pub fn get_with_prefix(&self, cidr: &Cidr) -> Vec<(Cidr, &T)> {
+        let mut results = Vec::new();
+        self.root(cidr).collect_with_prefix(cidr, cidr.family_root(), &mut results);
         results
     }
 
+    // Like longest_match(), but also returns the stored CIDR that matched
+    pub fn longest_match_with_prefix(&self, cidr: &Cidr) -> Option<(Cidr, &T)> {
+        self.get_with_prefix(cidr).pop()
+    }
+
+    // Returns up to k of the stored CIDRs covering the query, along with their
+    // data, from most to least specific
+    pub fn matches_desc(&self, cidr: &Cidr, k: usize) -> Vec<(Cidr, &T)> {
+        if k == 0 {
+            return Vec::new();
+        }
+        // Only the k deepest matches on the path need to be kept around
+        let mut deepest = VecDeque::with_capacity(k);
+        for entry in self.ancestors(cidr) {
+            if deepest.len() == k {
+                deepest.pop_front();
+            }
+            deepest.push_back(entry);
+        }
+        deepest.into_iter().rev().collect()
+    }
+
+    // Returns the data of the least specific stored CIDR covering the query,
+    // stopping at the first node with data on the way down
+    pub fn shortest_match(&self, cidr: &Cidr) -> Option<&T> {
+        self.shortest_match_with_prefix(cidr).map(|(_, d)| d)
+    }
+
+    // Like shortest_match(), but also returns the stored CIDR that matched. The
+    // CIDR comes back by value: nodes don't store their keys, so it is rebuilt
+    // from the path walked and there is no &Cidr in the tree to borrow.
+    pub fn shortest_match_with_prefix(&self, cidr: &Cidr) -> Option<(Cidr, &T)> {
+        self.ancestors(cidr).next()
+    }
+
     pub fn get_from_str(&self, cidr: &str) -> Vec<&T> {
-        self.get(&Cidr::from_str(cidr).unwrap())
+        self.get(Cidr::from_str(cidr).unwrap())
     }
 
-    pub fn insert(&mut self, cidr: &Cidr, data: Option<T>) {
-        // Search is over; this node is where the data goes
-        if cidr.length == 0 {
-            self.data = data;
-            return;
+    // Stores data at the given CIDR and returns the data it replaced, if any
+    pub fn insert<C: Into<Cidr>>(&mut self, cidr: C, data: T) -> Option<T> {
+        let cidr = cidr.into();
+        let replaced = self.root_mut(&cidr).insert(&cidr, data);
+        if replaced.is_none() {
+            self.len += 1;
         }
+        replaced
+    }
 
-        // Next cidr is the incoming cidr shifted left by one
-        let next_cidr = cidr.next();
+    // Like insert(), but refuses to replace data already stored at the CIDR
+    pub fn try_insert(&mut self, cidr: &Cidr, data: T) -> Result<(), OccupiedError<T>> {
+        if self.has_exact(cidr) {
+            return Err(OccupiedError {
+                cidr: *cidr,
+                value: data,
+            });
+        }
+        self.insert(cidr, data);
+        Ok(())
+    }
+
+    // Returns the entry for the given CIDR for in-place manipulation, walking
+    // the tree only once
+    pub fn entry(&mut self, cidr: &Cidr) -> Entry<'_, T> {
+        let (root, len) = match cidr.prefix {
+            Prefix::V4(_) => (&mut self.v4, &mut self.len),
+            Prefix::V6(_) => (&mut self.v6, &mut self.len),
+        };
+        let (node, rest) = root.descend_mut(*cidr);
+        let occupied = rest.length == 0 && node.data.is_some();
+        if occupied {
+            return Entry::Occupied(OccupiedEntry {
+                cidr: cidr.canonicalize(),
+                data: node.data.as_mut().unwrap(),
+            });
+        }
+        Entry::Vacant(VacantEntry {
+            cidr: cidr.canonicalize(),
+            node,
+            rest,
+            len,
+        })
+    }
+
+    // Returns a cursor positioned at the given CIDR, or None if the tree has no
+    // node there
+    pub fn cursor(&self, cidr: &Cidr) -> Option<Cursor<'_, T>> {
+        let mut path = vec![self.root(cidr)];
+        let mut key = cidr.family_root();
+        let mut rest = *cidr;
+        while rest.length > 0 {
+            let bit = rest.msbit();
+            path.push(path[path.len() - 1].child(bit)?);
+            key = key.child(bit);
+            rest = rest.next();
+        }
+        Some(Cursor { path, key })
+    }
+
+    // Returns a cursor positioned at the given CIDR that can modify the tree
+    pub fn cursor_mut(&mut self, cidr: &Cidr) -> CursorMut<'_, T> {
+        let key = cidr.canonicalize();
+        let (root, len) = match key.prefix {
+            Prefix::V4(_) => (&mut self.v4, &mut self.len),
+            Prefix::V6(_) => (&mut self.v6, &mut self.len),
+        };
+        let mut cursor = CursorMut {
+            path: vec![NonNull::from(root)],
+            len,
+            key: key.family_root(),
+            marker: PhantomData,
+        };
+        for i in 0..key.length as usize {
+            cursor.go_child(key.prefix.bit(i));
+        }
+        cursor
+    }
+
+    // Returns a read-only view of the entries within the given CIDR, or None if
+    // the tree holds nothing there
+    pub fn at(&self, cidr: &Cidr) -> Option<Subtree<'_, T>> {
+        self.subtree(cidr).map(|(key, node)| Subtree { key, node })
+    }
+
+    // Returns a view of the entries within the given CIDR that can change them
+    pub fn at_mut(&mut self, cidr: &Cidr) -> SubtreeMut<'_, T> {
+        SubtreeMut {
+            key: cidr.canonicalize(),
+            tree: self,
+        }
+    }
 
-        // TODO repetitive code
-        match cidr.msbit() {
-            0 => {
-                match self.zero {
-                    Some(ref mut child) => {
-                        child.insert(&next_cidr, data);
-                    },
-                    None => {
-                        let mut child = CidrTree::<T>::new();
-                        child.insert(&next_cidr, data);
-                        self.zero = Some(Box::new(child));
-                    },
+    // Stores data at the given CIDR, using the policy to decide what happens if
+    // the CIDR already has data. On success, returns whichever value didn't end
+    // up stored: the replaced data under Replace, or the new data under
+    // KeepExisting.
+    pub fn insert_with_policy(&mut self, cidr: &Cidr, data: T, policy: InsertPolicy<T>)
+        -> Result<Option<T>, OccupiedError<T>>
+    {
+        match policy {
+            InsertPolicy::Replace => Ok(self.insert(cidr, data)),
+            InsertPolicy::KeepExisting => match self.entry(cidr) {
+                Entry::Occupied(_) => Ok(Some(data)),
+                Entry::Vacant(entry) => {
+                    entry.insert(data);
+                    Ok(None)
+                },
+            },
+            InsertPolicy::Error => self.try_insert(cidr, data).map(|()| None),
+            InsertPolicy::Merge(merge) => {
+                if let Some(node) = self.root_mut(cidr).find_mut(cidr) {
+                    if let Some(existing) = node.data.take() {
+                        node.data = Some(merge(cidr, existing, data));
+                        return Ok(None);
+                    }
                 }
+                self.insert(cidr, data);
+                Ok(None)
             },
-            _ => {
-                match self.one {
-                    Some(ref mut child) => {
-                        child.insert(&next_cidr, data);
-                    },
-                    None => {
-                        let mut child = CidrTree::<T>::new();
-                        child.insert(&next_cidr, data);
-                        self.one = Some(Box::new(child));
-                    },
+        }
+    }
+
+    // Removes and returns the data stored at exactly the given CIDR. Any
+    // branches left holding no data are pruned so the tree doesn't keep growing
+    // as entries come and go.
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        let removed = self.root_mut(cidr).remove(cidr);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    // Removes the most specific stored CIDR covering the query and returns it
+    // along with its data, e.g. to hand out each allocation only once
+    pub fn pop_most_specific(&mut self, cidr: &Cidr) -> Option<(Cidr, T)> {
+        let (key, _) = self.longest_match_with_prefix(cidr)?;
+        self.remove(&key).map(|data| (key, data))
+    }
+
+    // Keeps only the entries for which f returns true, pruning any branches
+    // left empty
+    pub fn retain<F>(&mut self, mut f: F) where F: FnMut(&Cidr, &T) -> bool {
+        let removed = self.v4.retain(Cidr::new(Prefix::V4([0; 4]), 0), &mut f) +
+                      self.v6.retain(Cidr::new(Prefix::V6([0; 16]), 0), &mut f);
+        self.len -= removed;
+    }
+
+    // Removes every entry whose CIDR the predicate matches, e.g. all prefixes
+    // longer than /24, and returns how many were removed
+    pub fn remove_where<F>(&mut self, mut f: F) -> usize where F: FnMut(&Cidr) -> bool {
+        let before = self.len;
+        self.retain(|cidr, _| !f(cidr));
+        before - self.len
+    }
+
+    // Returns the number of CIDRs with data stored in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Removes every entry, leaving the tree empty but still usable in place
+    pub fn clear(&mut self) {
+        self.v4 = Node::new();
+        self.v6 = Node::new();
+        self.len = 0;
+    }
+
+    // Iterates over every stored CIDR and its data in prefix order: IPv4 before
+    // IPv6, then by address, with shorter prefixes before the longer ones they
+    // contain
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![(Cidr::new(Prefix::V6([0; 16]), 0), &self.v6),
+                        (Cidr::new(Prefix::V4([0; 4]), 0), &self.v4)],
+        }
+    }
+
+    // Like get(), but returns copies of the data so they can outlive the borrow
+    // of the tree
+    pub fn get_cloned(&self, cidr: &Cidr) -> Vec<T> where T: Clone {
+        self.matches(cidr).cloned().collect()
+    }
+
+    // Like longest_match(), but returns a copy of the data
+    pub fn longest_match_cloned(&self, cidr: &Cidr) -> Option<T> where T: Clone {
+        self.longest_match(cidr).cloned()
+    }
+
+    // Runs longest_match() for each query, returning the results in the same
+    // order. Queries are visited in address order so that each one resumes
+    // from the part of the previous query's path it shares.
+    pub fn lookup_many(&self, cidrs: &[Cidr]) -> Vec<Option<&T>> {
+        let mut order = (0..cidrs.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| (cidrs[i].prefix.bits(), cidrs[i].prefix.to_int()));
+
+        let mut results = vec![None; cidrs.len()];
+        // Nodes on the current path, each with the deepest data at or above it
+        let mut path = Vec::<(&Node<T>, Option<&T>)>::new();
+        let mut prev: Option<Cidr> = None;
+        for i in order {
+            let cidr = cidrs[i];
+            let bits = cidr.prefix.bits();
+            match prev {
+                Some(p) if p.prefix.bits() == bits => {
+                    let differing = p.prefix.to_int() ^ cidr.prefix.to_int();
+                    let common = differing.leading_zeros() as u8 - (128 - bits);
+                    path.truncate(common.min(cidr.length) as usize + 1);
+                },
+                _ => {
+                    let root = self.root(&cidr);
+                    path = vec![(root, root.data.as_ref())];
+                },
+            }
+            let value = cidr.prefix.to_int();
+            while path.len() <= cidr.length as usize {
+                let (node, best) = path[path.len() - 1];
+                let bit = (value >> (bits as usize - path.len())) as u8 & 1;
+                match node.child(bit) {
+                    Some(child) => path.push((child, child.data.as_ref().or(best))),
+                    None => break,
                 }
+            }
+            results[i] = path[path.len() - 1].1;
+            prev = Some(cidr);
+        }
+        results
+    }
+
+    // Lazily yields the same data as get(), without allocating
+    pub fn matches<'a>(&'a self, cidr: &Cidr) -> impl Iterator<Item = &'a T> {
+        self.ancestors(cidr).map(|(_, d)| d)
+    }
+
+    // Folds f over the data of every stored CIDR covering the query, from least
+    // to most specific, in a single walk down the tree
+    pub fn fold_matches<B, F>(&self, cidr: &Cidr, init: B, f: F) -> B where F: FnMut(B, &T) -> B {
+        self.matches(cidr).fold(init, f)
+    }
+
+    // Lazily yields each stored CIDR covering the given one, along with its
+    // data, from least to most specific. Same results as get_with_prefix().
+    pub fn ancestors(&self, cidr: &Cidr) -> Ancestors<'_, T> {
+        Ancestors {
+            node: Some(self.root(cidr)),
+            key: cidr.family_root(),
+            rest: *cidr,
+        }
+    }
+
+    // Iterates over the stored CIDRs that fall within the given CIDR, including
+    // the CIDR itself, in the same order as iter()
+    pub fn covered_by(&self, cidr: &Cidr) -> Iter<'_, T> {
+        Iter {
+            stack: self.subtree(cidr).into_iter().collect(),
+        }
+    }
+
+    // Removes every entry within the given CIDR, including the CIDR itself, in
+    // one operation. The removed entries are yielded in the same order as
+    // iter(); dropping the iterator simply discards them.
+    pub fn remove_subtree(&mut self, cidr: &Cidr) -> IntoIter<T> {
+        let detached = self.root_mut(cidr).detach(cidr);
+        let stack = match detached {
+            Some(node) => {
+                self.len -= node.count();
+                vec![(cidr.canonicalize(), node)]
             },
+            None => Vec::new(),
+        };
+        IntoIter {
+            stack,
+        }
+    }
+
+    // Calls f on every node of the tree, IPv4 before IPv6 and zero branches
+    // before one branches, passing the CIDR the node represents, its depth and
+    // its data (nodes that only lead to deeper entries have none). Returning
+    // ControlFlow::Break from f stops the walk and is passed back to the caller.
+    pub fn walk<B, F>(&self, order: WalkOrder, mut f: F) -> ControlFlow<B>
+        where F: FnMut(&Cidr, u8, Option<&T>) -> ControlFlow<B>
+    {
+        self.v4.walk(Cidr::new(Prefix::V4([0; 4]), 0), order, &mut f)?;
+        self.v6.walk(Cidr::new(Prefix::V6([0; 16]), 0), order, &mut f)
+    }
+
+    // Like walk(), but lets f rewrite, add or remove the data of each node in
+    // place. Branches left without data are pruned once the walk is over.
+    pub fn walk_mut<B, F>(&mut self, order: WalkOrder, mut f: F) -> ControlFlow<B>
+        where F: FnMut(&Cidr, u8, &mut Option<T>) -> ControlFlow<B>
+    {
+        let len = &mut self.len;
+        self.v4.walk_mut(Cidr::new(Prefix::V4([0; 4]), 0), order, &mut f, len)?;
+        self.v6.walk_mut(Cidr::new(Prefix::V6([0; 16]), 0), order, &mut f, len)
+    }
+
+    // Moves every entry within the given CIDR, including the CIDR itself, into
+    // a new tree, leaving the rest of this tree intact
+    pub fn split_off(&mut self, cidr: &Cidr) -> CidrTree<T> {
+        let mut split = CidrTree::with_match_policy(self.match_policy);
+        if let Some(node) = self.root_mut(cidr).detach(cidr) {
+            let count = node.count();
+            self.len -= count;
+            split.len = count;
+            split.root_mut(cidr).attach(cidr, node);
+        }
+        split
+    }
+
+    // Combines another tree into this one. Where both trees have data for the
+    // same CIDR, resolve is called with the CIDR, this tree's data and the other
+    // tree's data, and its result is stored.
+    pub fn merge<F>(mut self, other: CidrTree<T>, mut resolve: F) -> CidrTree<T>
+        where F: FnMut(&Cidr, T, T) -> T
+    {
+        let CidrTree { v4, v6, len, .. } = other;
+        self.len += len;
+        self.v4.merge(v4, Cidr::new(Prefix::V4([0; 4]), 0), &mut resolve, &mut self.len);
+        self.v6.merge(v6, Cidr::new(Prefix::V6([0; 16]), 0), &mut resolve, &mut self.len);
+        self
+    }
+
+    // Returns the changes that would turn this tree into the other one
+    pub fn diff(&self, other: &CidrTree<T>) -> Changeset<T> where T: PartialEq + Clone {
+        let mut changes = Changeset {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        Node::diff(Some(&self.v4), Some(&other.v4), Cidr::new(Prefix::V4([0; 4]), 0), &mut changes);
+        Node::diff(Some(&self.v6), Some(&other.v6), Cidr::new(Prefix::V6([0; 16]), 0), &mut changes);
+        changes
+    }
+
+    // Applies a changeset produced by diff(), so that a tree equal to the one
+    // diffed from ends up equal to the one diffed against
+    pub fn apply_changeset(&mut self, changes: Changeset<T>) {
+        for (cidr, _) in changes.removed {
+            self.remove(&cidr);
+        }
+        for (cidr, data) in changes.added.into_iter().chain(changes.changed) {
+            self.insert(cidr, data);
+        }
+    }
+
+    // Iterates over the stored CIDRs that overlap the inclusive address range,
+    // in the same order as iter(). Nothing is yielded if the two ends of the
+    // range are of different address families.
+    pub fn range(&self, range: RangeInclusive<IpAddr>) -> Range<'_, T> {
+        let (start, end) = range.into_inner();
+        let (start, end) = match (start, end) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (Prefix::from_ip(start), Prefix::from_ip(end)),
+            _ => return Range { stack: Vec::new(), start: 0, end: 0 },
+        };
+        let root = Cidr::new(start.zeroed(), 0);
+        let (start, end) = (start.to_int(), end.to_int());
+        Range {
+            stack: if start <= end { vec![(root, self.root(&root))] } else { Vec::new() },
+            start,
+            end,
+        }
+    }
+
+    // Replaces every pair of sibling entries that hold equal data and have
+    // nothing below them with a single entry for their parent, e.g. two /25s
+    // with the same data become one /24. Collapsing repeats up the tree as far
+    // as possible, and never overwrites data already stored at a parent.
+    pub fn compact(&mut self) where T: PartialEq {
+        let len = &mut self.len;
+        self.v4.compact(len);
+        self.v6.compact(len);
+    }
+
+    // Removes every entry whose data equals that of the most specific stored
+    // CIDR covering it, as such entries make no difference to lookups. Returns
+    // how many were removed.
+    pub fn dedup_covered(&mut self) -> usize where T: PartialEq {
+        let removed = self.v4.dedup_covered(None) + self.v6.dedup_covered(None);
+        self.len -= removed;
+        removed
+    }
+
+    // Returns the smallest set of CIDRs covering exactly the addresses covered
+    // by the stored CIDRs, ignoring their data, in prefix order. Nested entries
+    // are dropped and adjacent halves are merged, e.g. 10.0.0.0/25 and
+    // 10.0.0.128/25 become 10.0.0.0/24.
+    pub fn minimal_cover(&self) -> Vec<Cidr> {
+        let mut cover = Vec::new();
+        self.cover_where(&mut cover, &|_| true);
+        cover
+    }
+
+    // Like minimal_cover(), but computed separately for the entries holding
+    // each distinct value, so each CIDR comes with the data it aggregates.
+    // Values are grouped in the order they first appear in iter().
+    pub fn minimal_cover_by_value(&self) -> Vec<(Cidr, &T)> where T: PartialEq {
+        let mut values = Vec::<&T>::new();
+        for (_, data) in self.iter() {
+            if !values.contains(&data) {
+                values.push(data);
+            }
+        }
+        let mut results = Vec::new();
+        for value in values {
+            let mut cover = Vec::new();
+            self.cover_where(&mut cover, &|data| data == value);
+            results.extend(cover.into_iter().map(|cidr| (cidr, value)));
+        }
+        results
+    }
+
+    fn cover_where<F>(&self, cover: &mut Vec<Cidr>, f: &F) where F: Fn(&T) -> bool {
+        for (root, node) in [(Cidr::new(Prefix::V4([0; 4]), 0), &self.v4),
+                             (Cidr::new(Prefix::V6([0; 16]), 0), &self.v6)] {
+            if node.cover_where(root, cover, f) {
+                cover.push(root);
+            }
+        }
+    }
+
+    // Returns statistics about the shape of the tree, for understanding its
+    // memory use
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        self.v4.collect_stats(0, &mut stats);
+        self.v6.collect_stats(0, &mut stats);
+        stats
+    }
+
+    // Verifies the tree's structural invariants, returning the first violation
+    // found. Meant for tests and fuzzing; a tree only modified through this API
+    // should always pass.
+    pub fn check_invariants(&self) -> Result<(), InvariantError> {
+        self.v4.check_invariants(Cidr::new(Prefix::V4([0; 4]), 0))?;
+        self.v6.check_invariants(Cidr::new(Prefix::V6([0; 16]), 0))?;
+        let counted = self.v4.count() + self.v6.count();
+        if counted != self.len {
+            return Err(InvariantError::LenMismatch { len: self.len, counted });
+        }
+        Ok(())
+    }
+
+    // Returns the number of distinct addresses covered by the stored CIDRs,
+    // counting addresses covered by nested CIDRs only once. As ::/0 alone holds
+    // one address more than a u128 can count, the result saturates at
+    // u128::MAX.
+    pub fn covered_count(&self) -> u128 {
+        let v4 = self.v4.covered_count(Cidr::new(Prefix::V4([0; 4]), 0));
+        let v6 = self.v6.covered_count(Cidr::new(Prefix::V6([0; 16]), 0));
+        v4.saturating_add(v6)
+    }
+
+    // Iterates over the largest CIDRs within the given universe that aren't
+    // covered by any stored CIDR, in address order
+    pub fn gaps(&self, universe: &Cidr) -> Gaps<'_, T> {
+        let stack = match (self.covers(universe), self.subtree(universe)) {
+            (true, _) => Vec::new(),
+            (false, Some((key, node))) => vec![(key, Some(node))],
+            (false, None) => vec![(universe.canonicalize(), None)],
+        };
+        Gaps {
+            stack,
+        }
+    }
+
+    // Returns the lowest address within the given CIDR that isn't covered by
+    // any stored CIDR
+    pub fn find_first_uncovered(&self, cidr: &Cidr) -> Option<IpAddr> {
+        self.gaps(cidr).next().map(|gap| gap.prefix.to_ip_addr())
+    }
+
+    // Returns the stored CIDR starting closest above the given address, i.e. the
+    // one with the lowest first address greater than it. Of CIDRs starting at
+    // the same address, the most specific is returned.
+    pub fn next_covered_after(&self, ip: IpAddr) -> Option<(Cidr, &T)> {
+        let root = Cidr::new(Prefix::from_ip(ip).zeroed(), 0);
+        self.root(&root).next_after(root, Prefix::from_ip(ip).to_int())
+    }
+
+    // Returns the stored CIDR ending closest below the given address, i.e. the
+    // one with the highest last address less than it. Of CIDRs ending at the
+    // same address, the most specific is returned.
+    pub fn prev_covered_before(&self, ip: IpAddr) -> Option<(Cidr, &T)> {
+        let root = Cidr::new(Prefix::from_ip(ip).zeroed(), 0);
+        self.root(&root).prev_before(root, Prefix::from_ip(ip).to_int())
+    }
+
+    // Removes every entry from the tree, yielding them in the same order as
+    // iter(). The tree is left empty even if the iterator isn't used up.
+    pub fn drain(&mut self) -> IntoIter<T> {
+        let match_policy = self.match_policy;
+        mem::replace(self, CidrTree::with_match_policy(match_policy)).into_iter()
+    }
+
+    // Finds the node for the given CIDR, along with its key (the CIDR with any
+    // bits past its length cleared)
+    fn subtree(&self, cidr: &Cidr) -> Option<(Cidr, &Node<T>)> {
+        let mut key = cidr.family_root();
+        let mut node = self.root(cidr);
+        let mut rest = *cidr;
+        while rest.length > 0 {
+            let bit = rest.msbit();
+            node = node.child(bit)?;
+            key = key.child(bit);
+            rest = rest.next();
+        }
+        Some((key, node))
+    }
+
+    fn root(&self, cidr: &Cidr) -> &Node<T> {
+        match cidr.prefix {
+            Prefix::V4(_) => &self.v4,
+            Prefix::V6(_) => &self.v6,
+        }
+    }
+
+    fn root_mut(&mut self, cidr: &Cidr) -> &mut Node<T> {
+        match cidr.prefix {
+            Prefix::V4(_) => &mut self.v4,
+            Prefix::V6(_) => &mut self.v6,
         }
     }
 }
 
-#[test]
-fn test_insert_v4() {
-    let mut t = CidrTree::<String>::new();
+impl<'a, T> IntoIterator for &'a CidrTree<T> {
+    type Item = (Cidr, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+// Iterator over the entries of a CidrTree, created by CidrTree::iter()
+pub struct Iter<'a, T> where T: 'a {
+    // Nodes still to be visited, along with the CIDR each one represents
+    stack: Vec<(Cidr, &'a Node<T>)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Cidr, &'a T);
+
+    fn next(&mut self) -> Option<(Cidr, &'a T)> {
+        while let Some((key, node)) = self.stack.pop() {
+            // Push one before zero so the zero branch is visited first
+            if let Some(ref child) = node.one {
+                self.stack.push((key.child(1), child));
+            }
+            if let Some(ref child) = node.zero {
+                self.stack.push((key.child(0), child));
+            }
+            if let Some(ref d) = node.data {
+                return Some((key, d));
+            }
+        }
+        None
+    }
+}
 
-    t.insert(&Cidr::from_str("128.0.0.0/1").unwrap(), Some("first".to_string()));
+// Iterator over the entries overlapping an address range, created by
+// CidrTree::range()
+pub struct Range<'a, T> where T: 'a {
+    stack: Vec<(Cidr, &'a Node<T>)>,
+    // The range, as integer addresses
+    start: u128,
+    end: u128,
+}
 
-    assert!(t.get_from_str(&"1.0.0.0").is_empty());
-    assert!(t.get_from_str(&"128.0.0.0").len() == 1);
-    assert!(t.get_from_str(&"255.0.0.0").len() == 1);
-    assert!(t.get_from_str(&"128.0.0.0")[0] == "first");
-    assert!(t.get_from_str(&"128.1.0.0").len() == 1);
-    assert!(t.get_from_str(&"128.0.0.0/8").len() == 1);
+impl<'a, T> Iterator for Range<'a, T> {
+    type Item = (Cidr, &'a T);
 
-    t.insert(&Cidr::from_str("255.0.0.0/2").unwrap(), Some("second".to_string()));
+    fn next(&mut self) -> Option<(Cidr, &'a T)> {
+        while let Some((key, node)) = self.stack.pop() {
+            // Skip whole branches that lie outside the range
+            let (first, last) = key.span();
+            if last < self.start || first > self.end {
+                continue;
+            }
+            if let Some(ref child) = node.one {
+                self.stack.push((key.child(1), child));
+            }
+            if let Some(ref child) = node.zero {
+                self.stack.push((key.child(0), child));
+            }
+            if let Some(ref d) = node.data {
+                return Some((key, d));
+            }
+        }
+        None
+    }
+}
 
-    assert!(t.get_from_str(&"1.0.0.0").is_empty());
-    assert!(t.get_from_str(&"128.0.0.0").len() == 1);
-    assert!(t.get_from_str(&"255.0.0.0").len() == 2);
-    assert!(t.get_from_str(&"128.0.0.0")[0] == "first");
-    assert!(t.get_from_str(&"128.1.0.0").len() == 1);
-    assert!(t.get_from_str(&"128.0.0.0/8").len() == 1);
-    assert!(t.get_from_str(&"255.0.0.0").len() == 2);
-    assert!(t.get_from_str(&"255.1.0.0").len() == 2);
-    assert!(t.get_from_str(&"255.0.0.0/8").len() == 2);
+// Iterator over the uncovered parts of a CIDR, created by CidrTree::gaps()
+pub struct Gaps<'a, T> where T: 'a {
+    // CIDRs still to be examined, with their nodes if the tree has them
+    stack: Vec<(Cidr, Option<&'a Node<T>>)>,
 }
 
-#[test]
-fn test_insert_v6() {
-    let mut t = CidrTree::<String>::new();
+impl<'a, T> Iterator for Gaps<'a, T> {
+    type Item = Cidr;
 
-    t.insert(&Cidr::from_str("8000:0:0:0::/1").unwrap(), Some("first".to_string()));
-    assert!(t.get_from_str(&"0001:0:0:0::").is_empty());
-    assert!(t.get_from_str(&"8000::").len() == 1);
-    assert!(t.get_from_str(&"F000::").len() == 1);
-    assert!(t.get_from_str(&"8000::1").len() == 1);
-    assert!(t.get_from_str(&"8000::/8").len() == 1);
+    fn next(&mut self) -> Option<Cidr> {
+        while let Some((key, node)) = self.stack.pop() {
+            match node {
+                // Nothing stored anywhere in this CIDR
+                None => return Some(key),
+                Some(node) if node.is_prunable() => return Some(key),
+                // Everything in this CIDR is covered
+                Some(node) if node.data.is_some() => {},
+                Some(node) => {
+                    self.stack.push((key.child(1), node.child(1)));
+                    self.stack.push((key.child(0), node.child(0)));
+                },
+            }
+        }
+        None
+    }
+}
+
+// Iterator over the entries covering a CIDR, created by CidrTree::ancestors()
+pub struct Ancestors<'a, T> where T: 'a {
+    // Next node on the path to the queried CIDR, the CIDR it represents, and
+    // the part of the query still to be walked below it
+    node: Option<&'a Node<T>>,
+    key: Cidr,
+    rest: Cidr,
+}
+
+impl<'a, T> Iterator for Ancestors<'a, T> {
+    type Item = (Cidr, &'a T);
+
+    fn next(&mut self) -> Option<(Cidr, &'a T)> {
+        while let Some(node) = self.node {
+            let key = self.key;
+            if self.rest.length > 0 {
+                let bit = self.rest.msbit();
+                self.node = node.child(bit);
+                self.key = key.child(bit);
+                self.rest = self.rest.next();
+            } else {
+                self.node = None;
+            }
+            if let Some(ref d) = node.data {
+                return Some((key, d));
+            }
+        }
+        None
+    }
+}
+
+impl<T> IntoIterator for CidrTree<T> {
+    type Item = (Cidr, T);
+    type IntoIter = IntoIter<T>;
+
+    // Consumes the tree, yielding its entries in the same order as iter()
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            stack: vec![(Cidr::new(Prefix::V6([0; 16]), 0), self.v6),
+                        (Cidr::new(Prefix::V4([0; 4]), 0), self.v4)],
+        }
+    }
+}
+
+// Owning iterator over the entries of a CidrTree
+pub struct IntoIter<T> {
+    stack: Vec<(Cidr, Node<T>)>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = (Cidr, T);
+
+    fn next(&mut self) -> Option<(Cidr, T)> {
+        while let Some((key, node)) = self.stack.pop() {
+            let Node { zero, one, data } = node;
+            if let Some(child) = one {
+                self.stack.push((key.child(1), *child));
+            }
+            if let Some(child) = zero {
+                self.stack.push((key.child(0), *child));
+            }
+            if let Some(d) = data {
+                return Some((key, d));
+            }
+        }
+        None
+    }
+}
+
+impl<T> FromIterator<(Cidr, T)> for CidrTree<T> {
+    fn from_iter<I: IntoIterator<Item = (Cidr, T)>>(iter: I) -> CidrTree<T> {
+        let mut tree = CidrTree::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+// Later entries overwrite earlier ones for the same CIDR, as with insert()
+impl<T> Extend<(Cidr, T)> for CidrTree<T> {
+    fn extend<I: IntoIterator<Item = (Cidr, T)>>(&mut self, iter: I) {
+        for (cidr, data) in iter {
+            self.insert(cidr, data);
+        }
+    }
+}
+
+// A view into a single CIDR of a CidrTree, created by CidrTree::entry()
+pub enum Entry<'a, T> where T: 'a {
+    Occupied(OccupiedEntry<'a, T>),
+    Vacant(VacantEntry<'a, T>),
+}
+
+// An entry whose CIDR already has data
+pub struct OccupiedEntry<'a, T> where T: 'a {
+    cidr: Cidr,
+    data: &'a mut T,
+}
+
+// An entry whose CIDR has no data yet
+pub struct VacantEntry<'a, T> where T: 'a {
+    cidr: Cidr,
+    // Deepest existing node on the path to the CIDR, and the part of the CIDR
+    // that still has to be walked from there
+    node: &'a mut Node<T>,
+    rest: Cidr,
+    len: &'a mut usize,
+}
+
+impl<'a, T> Entry<'a, T> {
+    pub fn key(&self) -> &Cidr {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: T) -> &'a mut T {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> T>(self, default: F) -> &'a mut T {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut T where T: Default {
+        self.or_insert_with(T::default)
+    }
+
+    // Applies f to the data if the entry is occupied
+    pub fn and_modify<F: FnOnce(&mut T)>(self, f: F) -> Entry<'a, T> {
+        match self {
+            Entry::Occupied(entry) => {
+                f(entry.data);
+                Entry::Occupied(entry)
+            },
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<'a, T> OccupiedEntry<'a, T> {
+    pub fn key(&self) -> &Cidr {
+        &self.cidr
+    }
+
+    pub fn get(&self) -> &T {
+        self.data
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data
+    }
+
+    pub fn into_mut(self) -> &'a mut T {
+        self.data
+    }
+
+    // Replaces the entry's data, returning the old data
+    pub fn insert(&mut self, data: T) -> T {
+        mem::replace(self.data, data)
+    }
+}
+
+impl<'a, T> VacantEntry<'a, T> {
+    pub fn key(&self) -> &Cidr {
+        &self.cidr
+    }
+
+    // Stores data at the entry's CIDR and returns a reference to it
+    pub fn insert(self, data: T) -> &'a mut T {
+        *self.len += 1;
+        self.node.insert_vacant(&self.rest, data)
+    }
+}
+
+// A read-only position in a CidrTree that can be moved one bit at a time,
+// created by CidrTree::cursor(). It only visits nodes that exist in the tree.
+pub struct Cursor<'a, T> where T: 'a {
+    // The nodes from the family root down to the current one
+    path: Vec<&'a Node<T>>,
+    key: Cidr,
+}
+
+impl<'a, T> Cursor<'a, T> {
+    // The CIDR the cursor is positioned at
+    pub fn key(&self) -> Cidr {
+        self.key
+    }
+
+    pub fn data(&self) -> Option<&'a T> {
+        self.node().data.as_ref()
+    }
+
+    // Returns true if the tree has a node below the current one on the given
+    // side, i.e. if some CIDR under key().child(bit) is stored
+    pub fn has_child(&self, bit: u8) -> bool {
+        self.node().child(bit).is_some()
+    }
+
+    // Moves to the child on the given side, returning false and staying put if
+    // it doesn't exist
+    pub fn go_child(&mut self, bit: u8) -> bool {
+        match self.node().child(bit) {
+            Some(child) => {
+                self.path.push(child);
+                self.key = self.key.child(bit);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn go_zero(&mut self) -> bool {
+        self.go_child(0)
+    }
+
+    pub fn go_one(&mut self) -> bool {
+        self.go_child(1)
+    }
+
+    // Moves to the parent, returning false at the family root
+    pub fn up(&mut self) -> bool {
+        if self.key.length == 0 {
+            return false;
+        }
+        self.path.pop();
+        self.key = Cidr::new(self.key.prefix, self.key.length - 1).canonicalize();
+        true
+    }
+
+    fn node(&self) -> &'a Node<T> {
+        self.path[self.path.len() - 1]
+    }
+}
+
+// A position in a CidrTree that can be moved one bit at a time and can change
+// the data where it is, created by CidrTree::cursor_mut(). Unlike Cursor it may
+// move to CIDRs the tree has no node for yet, so that data can be inserted
+// there. Like Cursor it keeps the path of nodes it has descended, so every
+// operation is O(1) at the current position, except insert(), which creates
+// the missing nodes.
+pub struct CursorMut<'a, T> where T: 'a {
+    // The nodes from the family root down to the current one, or to the
+    // deepest one that exists on the way to it. Each is owned by the Box in its
+    // parent, so it stays put until the cursor itself prunes it; the borrow of
+    // the tree in `marker` keeps anything else from touching them.
+    path: Vec<NonNull<Node<T>>>,
+    len: &'a mut usize,
+    key: Cidr,
+    marker: PhantomData<&'a mut Node<T>>,
+}
+
+impl<'a, T> CursorMut<'a, T> {
+    pub fn key(&self) -> Cidr {
+        self.key
+    }
+
+    pub fn data(&self) -> Option<&T> {
+        self.node().and_then(|node| node.data.as_ref())
+    }
+
+    pub fn data_mut(&mut self) -> Option<&mut T> {
+        self.node_mut().and_then(|node| node.data.as_mut())
+    }
+
+    // Returns true if some stored CIDR lies under key().child(bit)
+    pub fn has_child(&self, bit: u8) -> bool {
+        self.node().is_some_and(|node| node.child(bit).is_some())
+    }
+
+    // Stores data at the current CIDR, returning what was there before
+    pub fn insert(&mut self, data: T) -> Option<T> {
+        while self.path.len() <= self.key.length as usize {
+            let last = self.path.len() - 1;
+            let bit = self.key.prefix.bit(last);
+            let child = self.path_node_mut(last).child_slot(bit).get_or_insert_with(|| Box::new(Node::new()));
+            let child = NonNull::from(&mut **child);
+            self.path.push(child);
+        }
+        let replaced = self.node_mut().unwrap().data.replace(data);
+        if replaced.is_none() {
+            *self.len += 1;
+        }
+        replaced
+    }
+
+    // Removes the data at the current CIDR, pruning nodes left empty. The
+    // cursor stays where it is even if its node is pruned.
+    pub fn remove(&mut self) -> Option<T> {
+        let removed = self.node_mut()?.data.take();
+        if removed.is_some() {
+            *self.len -= 1;
+        }
+        while self.path.len() > 1 {
+            let last = self.path.len() - 1;
+            if !self.path_node(last).is_prunable() {
+                break;
+            }
+            // The node leaves the path before its Box is dropped, so no
+            // dangling pointer is left behind
+            self.path.pop();
+            let bit = self.key.prefix.bit(last - 1);
+            self.path_node_mut(last - 1).child_slot(bit).take();
+        }
+        removed
+    }
+
+    // Moves to the child on the given side, returning false and staying put if
+    // the cursor is already at a full-length prefix
+    pub fn go_child(&mut self, bit: u8) -> bool {
+        if self.key.length == self.key.prefix.bits() {
+            return false;
+        }
+        if let Some(child) = self.node_mut().and_then(|node| node.child_slot(bit).as_deref_mut()) {
+            let child = NonNull::from(child);
+            self.path.push(child);
+        }
+        self.key = self.key.child(bit);
+        true
+    }
+
+    pub fn go_zero(&mut self) -> bool {
+        self.go_child(0)
+    }
+
+    pub fn go_one(&mut self) -> bool {
+        self.go_child(1)
+    }
+
+    // Moves to the parent, returning false at the family root
+    pub fn up(&mut self) -> bool {
+        if self.key.length == 0 {
+            return false;
+        }
+        if self.path.len() == self.key.length as usize + 1 {
+            self.path.pop();
+        }
+        self.key = Cidr::new(self.key.prefix, self.key.length - 1).canonicalize();
+        true
+    }
+
+    // The node at the current CIDR, if the tree has one
+    fn node(&self) -> Option<&Node<T>> {
+        match self.path.len() == self.key.length as usize + 1 {
+            true => Some(self.path_node(self.path.len() - 1)),
+            false => None,
+        }
+    }
+
+    fn node_mut(&mut self) -> Option<&mut Node<T>> {
+        match self.path.len() == self.key.length as usize + 1 {
+            true => Some(self.path_node_mut(self.path.len() - 1)),
+            false => None,
+        }
+    }
+
+    // The only two places the cursor dereferences its path. Both rely on what
+    // holds for every pointer in `path`:
+    //
+    // - It was made from a `&mut` reached through the `&'a mut CidrTree` that
+    //   created the cursor: the root from the tree's own field, each other
+    //   node from the Box in the node before it. That borrow lasts as long as
+    //   the cursor, so nothing outside the cursor can read or write the nodes.
+    // - Its node is alive. Nodes live in their own Box allocations, which stay
+    //   put however the parent is borrowed, and the cursor frees a node only in
+    //   remove(), after popping it off the path.
+    fn path_node(&self, i: usize) -> &Node<T> {
+        // SAFETY: the pointer is valid and the tree is borrowed, as above. The
+        // shared reference is tied to `&self`, so the cursor cannot hand out a
+        // `&mut` to any node while it is alive.
+        unsafe { self.path[i].as_ref() }
+    }
+
+    fn path_node_mut(&mut self, i: usize) -> &mut Node<T> {
+        // SAFETY: the pointer is valid and the tree is borrowed, as above. The
+        // reference is tied to `&mut self`, so it is the only one into the tree
+        // while it lives. It covers just this node's own allocation: its
+        // children sit behind Boxes, so a pointer to a child further down the
+        // path is not invalidated by it, only reached through it.
+        unsafe { self.path[i].as_mut() }
+    }
+}
+
+// A read-only view of the part of a CidrTree within one CIDR, created by
+// CidrTree::at(). Queries take full CIDRs; those outside the view's block find
+// nothing.
+pub struct Subtree<'a, T> where T: 'a {
+    key: Cidr,
+    node: &'a Node<T>,
+}
+
+impl<'a, T> Subtree<'a, T> {
+    // The CIDR the view is rooted at
+    pub fn key(&self) -> Cidr {
+        self.key
+    }
+
+    // Like CidrTree::get(), ignoring entries above the view's root
+    pub fn get(&self, cidr: &Cidr) -> Vec<&'a T> {
+        self.relative(cidr).map_or(Vec::new(), |rest| self.node.get(&rest))
+    }
+
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<&'a T> {
+        self.relative(cidr).and_then(|rest| self.node.longest_match(&rest))
+    }
+
+    pub fn get_exact(&self, cidr: &Cidr) -> Option<&'a T> {
+        self.relative(cidr)
+            .and_then(|rest| self.node.find(&rest))
+            .and_then(|node| node.data.as_ref())
+    }
+
+    // Iterates over the entries within the view, in the same order as
+    // CidrTree::iter()
+    pub fn iter(&self) -> Iter<'a, T> {
+        Iter {
+            stack: vec![(self.key, self.node)],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.node.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node.count() == 0
+    }
+
+    // Returns the part of the CIDR below the view's root, if it is within it
+    fn relative(&self, cidr: &Cidr) -> Option<Cidr> {
+        if !self.key.contains(cidr) {
+            return None;
+        }
+        let mut rest = *cidr;
+        for _ in 0..self.key.length {
+            rest = rest.next();
+        }
+        Some(rest)
+    }
+}
+
+// A view of the part of a CidrTree within one CIDR that can change the entries
+// there, created by CidrTree::at_mut()
+pub struct SubtreeMut<'a, T> where T: 'a {
+    tree: &'a mut CidrTree<T>,
+    key: Cidr,
+}
+
+impl<'a, T> SubtreeMut<'a, T> {
+    pub fn key(&self) -> Cidr {
+        self.key
+    }
+
+    // Returns a read-only view of the same block, or None if it holds nothing
+    pub fn as_subtree(&self) -> Option<Subtree<'_, T>> {
+        self.tree.at(&self.key)
+    }
+
+    // Stores data at the given CIDR and returns the data it replaced, if any.
+    // Panics if the CIDR is outside the view's block.
+    pub fn insert(&mut self, cidr: &Cidr, data: T) -> Option<T> {
+        assert!(self.key.contains(cidr), "CIDR is outside of the subtree");
+        self.tree.insert(cidr, data)
+    }
+
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        match self.key.contains(cidr) {
+            true => self.tree.remove(cidr),
+            false => None,
+        }
+    }
+
+    pub fn get_exact(&self, cidr: &Cidr) -> Option<&T> {
+        self.as_subtree().and_then(|view| view.get_exact(cidr))
+    }
+
+    pub fn get_exact_mut(&mut self, cidr: &Cidr) -> Option<&mut T> {
+        if !self.key.contains(cidr) {
+            return None;
+        }
+        self.tree.root_mut(cidr).find_mut(cidr).and_then(|node| node.data.as_mut())
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.tree.covered_by(&self.key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_subtree().map_or(0, |view| view.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Removes every entry within the view's block
+    pub fn clear(&mut self) {
+        self.tree.remove_subtree(&self.key);
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            zero: None,
+            one: None,
+            data: None,
+        }
+    }
+
+    fn new_with_data(data: T) -> Node<T> {
+        Node {
+            zero: None,
+            one: None,
+            data: Some(data),
+        }
+    }
+
+    // Builds the subtree for key out of the leading entries of a sorted
+    // iterator that fall within it, counting the entries stored in len
+    fn from_sorted<I>(key: Cidr, iter: &mut Peekable<I>, len: &mut usize) -> Node<T>
+        where I: Iterator<Item = (Cidr, T)>
+    {
+        let mut node = Node::new();
+        let is_key = |&(cidr, _): &(Cidr, T)| cidr.length == key.length && key.contains(&cidr);
+        while let Some((_, data)) = iter.next_if(is_key) {
+            if node.data.replace(data).is_none() {
+                *len += 1;
+            }
+        }
+        if key.length < key.prefix.bits() {
+            for bit in 0..2 {
+                let child = key.child(bit);
+                if iter.peek().is_some_and(|(cidr, _)| child.contains(cidr)) {
+                    let built = Node::from_sorted(child, iter, len);
+                    match bit {
+                        0 => node.zero = Some(Box::new(built)),
+                        _ => node.one = Some(Box::new(built)),
+                    }
+                }
+            }
+        }
+        node
+    }
+
+    fn get(&self, cidr: &Cidr) -> Vec<&T> {
+        let mut results = Vec::<&T>::new();
+
+        // I might have something to contribute
+        if let Some(ref d) = self.data {
+            results.push(d);
+        }
+        // Nothing below this node can cover the queried CIDR
+        if cidr.length == 0 {
+            return results;
+        }
+        if let Some(child) = self.child(cidr.msbit()) {
+            results.extend(child.get(&cidr.next()));
+        }
+        results
+    }
+
+    fn longest_match(&self, cidr: &Cidr) -> Option<&T> {
+        let deeper = match cidr.length {
+            0 => None,
+            _ => self.child(cidr.msbit()).and_then(|child| child.longest_match(&cidr.next())),
+        };
+        deeper.or(self.data.as_ref())
+    }
+
+    fn collect_with_prefix<'a>(&'a self, cidr: &Cidr, key: Cidr, results: &mut Vec<(Cidr, &'a T)>) {
+        if let Some(ref d) = self.data {
+            results.push((key, d));
+        }
+        if cidr.length == 0 {
+            return;
+        }
+        let bit = cidr.msbit();
+        if let Some(child) = self.child(bit) {
+            child.collect_with_prefix(&cidr.next(), key.child(bit), results);
+        }
+    }
+
+    // Stores data at the given CIDR, creating nodes along the way as needed,
+    // and returns whatever was there before
+    fn insert(&mut self, cidr: &Cidr, data: T) -> Option<T> {
+        // Search is over; this node is where the data goes
+        if cidr.length == 0 {
+            return self.data.replace(data);
+        }
+
+        // Next cidr is the incoming cidr shifted left by one
+        let next_cidr = cidr.next();
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        child.get_or_insert_with(|| Box::new(Node::new())).insert(&next_cidr, data)
+    }
+
+    // Stores data at a CIDR known to have none, returning a reference to it
+    fn insert_vacant(&mut self, cidr: &Cidr, data: T) -> &mut T {
+        if cidr.length == 0 {
+            return self.data.insert(data);
+        }
+        let next_cidr = cidr.next();
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        child.get_or_insert_with(|| Box::new(Node::new())).insert_vacant(&next_cidr, data)
+    }
+
+    // Walks toward the given CIDR as far as the tree goes, returning the
+    // deepest node reached and what remains of the CIDR below it
+    fn descend_mut(&mut self, cidr: Cidr) -> (&mut Node<T>, Cidr) {
+        let mut node = self;
+        let mut cidr = cidr;
+        while cidr.length > 0 && node.child(cidr.msbit()).is_some() {
+            node = match cidr.msbit() {
+                0 => node.zero.as_mut().unwrap(),
+                _ => node.one.as_mut().unwrap(),
+            };
+            cidr = cidr.next();
+        }
+        (node, cidr)
+    }
+
+    fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        if cidr.length == 0 {
+            return self.data.take();
+        }
+
+        let next_cidr = cidr.next();
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        let removed = match *child {
+            Some(ref mut node) => node.remove(&next_cidr),
+            None => None,
+        };
+        if child.as_ref().is_some_and(|node| node.is_prunable()) {
+            *child = None;
+        }
+        removed
+    }
+
+    // Returns the node representing exactly the given CIDR, if it exists
+    fn find(&self, cidr: &Cidr) -> Option<&Node<T>> {
+        match cidr.length {
+            0 => Some(self),
+            _ => self.child(cidr.msbit()).and_then(|child| child.find(&cidr.next())),
+        }
+    }
+
+    // Writes the entries below this node (inclusive) for Display. level is the
+    // number of stored CIDRs above this node, used for indenting when nested.
+    fn fmt_entries(&self, key: Cidr, level: usize, nested: bool, f: &mut fmt::Formatter) -> fmt::Result
+        where T: fmt::Display
+    {
+        let mut level = level;
+        if let Some(ref d) = self.data {
+            if nested {
+                write!(f, "{:width$}", "", width = level * 2)?;
+            }
+            writeln!(f, "{} -> {}", key, d)?;
+            level += 1;
+        }
+        if let Some(ref child) = self.zero {
+            child.fmt_entries(key.child(0), level, nested, f)?;
+        }
+        if let Some(ref child) = self.one {
+            child.fmt_entries(key.child(1), level, nested, f)?;
+        }
+        Ok(())
+    }
+
+    fn walk<B, F>(&self, key: Cidr, order: WalkOrder, f: &mut F) -> ControlFlow<B>
+        where F: FnMut(&Cidr, u8, Option<&T>) -> ControlFlow<B>
+    {
+        if order == WalkOrder::PreOrder {
+            f(&key, key.length, self.data.as_ref())?;
+        }
+        if let Some(ref child) = self.zero {
+            child.walk(key.child(0), order, f)?;
+        }
+        if let Some(ref child) = self.one {
+            child.walk(key.child(1), order, f)?;
+        }
+        if order == WalkOrder::PostOrder {
+            f(&key, key.length, self.data.as_ref())?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Walks the nodes below this one (inclusive), keeping len in step with any
+    // data f adds or removes
+    fn walk_mut<B, F>(&mut self, key: Cidr, order: WalkOrder, f: &mut F, len: &mut usize) -> ControlFlow<B>
+        where F: FnMut(&Cidr, u8, &mut Option<T>) -> ControlFlow<B>
+    {
+        let mut flow = ControlFlow::Continue(());
+        if order == WalkOrder::PreOrder {
+            flow = self.visit_mut(key, f, len);
+        }
+        for bit in 0..2 {
+            if flow.is_break() {
+                break;
+            }
+            let child = match bit {
+                0 => &mut self.zero,
+                _ => &mut self.one,
+            };
+            if let Some(ref mut node) = *child {
+                flow = node.walk_mut(key.child(bit), order, f, len);
+            }
+            if child.as_ref().is_some_and(|node| node.is_prunable()) {
+                *child = None;
+            }
+        }
+        if order == WalkOrder::PostOrder && flow.is_continue() {
+            flow = self.visit_mut(key, f, len);
+        }
+        flow
+    }
+
+    fn visit_mut<B, F>(&mut self, key: Cidr, f: &mut F, len: &mut usize) -> ControlFlow<B>
+        where F: FnMut(&Cidr, u8, &mut Option<T>) -> ControlFlow<B>
+    {
+        let had_data = self.data.is_some();
+        let flow = f(&key, key.length, &mut self.data);
+        match (had_data, self.data.is_some()) {
+            (false, true) => *len += 1,
+            (true, false) => *len -= 1,
+            _ => {},
+        }
+        flow
+    }
+
+    fn compact(&mut self, len: &mut usize) where T: PartialEq {
+        for child in self.zero.iter_mut().chain(self.one.iter_mut()) {
+            child.compact(len);
+        }
+        if self.data.is_some() {
+            return;
+        }
+        let collapsible = match (self.zero.as_ref(), self.one.as_ref()) {
+            (Some(zero), Some(one)) => {
+                zero.is_leaf() && one.is_leaf() && zero.data.is_some() && zero.data == one.data
+            },
+            _ => false,
+        };
+        if collapsible {
+            self.data = self.zero.take().and_then(|zero| zero.data);
+            self.one = None;
+            *len -= 1;
+        }
+    }
+
+    // Removes the data of nodes below this one (inclusive) that is equal to the
+    // nearest data above them, returning how many were removed
+    fn dedup_covered(&mut self, inherited: Option<&T>) -> usize where T: PartialEq {
+        let mut removed = 0;
+        if self.data.is_some() && self.data.as_ref() == inherited {
+            self.data = None;
+            removed += 1;
+        }
+        let Node { ref mut zero, ref mut one, ref data } = *self;
+        let inherited = data.as_ref().or(inherited);
+        for child in [zero, one] {
+            if let Some(ref mut node) = *child {
+                removed += node.dedup_covered(inherited);
+            }
+            if child.as_ref().is_some_and(|node| node.is_prunable()) {
+                *child = None;
+            }
+        }
+        removed
+    }
+
+    fn collect_stats(&self, depth: u8, stats: &mut TreeStats) {
+        stats.max_depth = stats.max_depth.max(depth);
+        stats.node_count += 1;
+        if self.data.is_some() {
+            stats.data_node_count += 1;
+        }
+        match (self.zero.as_ref(), self.one.as_ref()) {
+            (None, None) => stats.leaf_count += 1,
+            (Some(_), Some(_)) => stats.two_children_count += 1,
+            _ => stats.one_child_count += 1,
+        }
+        for child in self.zero.iter().chain(self.one.iter()) {
+            child.collect_stats(depth + 1, stats);
+        }
+    }
+
+    // Appends the minimal cover of the addresses under this node held by
+    // entries matching f. Returns true instead, appending nothing, if those
+    // are all of the node's addresses, so the caller can merge it with its
+    // sibling.
+    fn cover_where<F>(&self, key: Cidr, cover: &mut Vec<Cidr>, f: &F) -> bool
+        where F: Fn(&T) -> bool
+    {
+        if self.data.as_ref().is_some_and(f) {
+            return true;
+        }
+        let mark = cover.len();
+        let zero_full = self.zero.as_ref()
+            .is_some_and(|child| child.cover_where(key.child(0), cover, f));
+        if zero_full {
+            cover.push(key.child(0));
+        }
+        let one_full = self.one.as_ref()
+            .is_some_and(|child| child.cover_where(key.child(1), cover, f));
+        if one_full {
+            cover.push(key.child(1));
+        }
+        if zero_full && one_full {
+            cover.truncate(mark);
+            return true;
+        }
+        false
+    }
+
+    fn check_invariants(&self, key: Cidr) -> Result<(), InvariantError> {
+        if key.length > 0 && self.is_prunable() {
+            return Err(InvariantError::EmptyLeaf(key));
+        }
+        if key.length == key.prefix.bits() && !self.is_leaf() {
+            return Err(InvariantError::TooDeep(key));
+        }
+        if let Some(ref child) = self.zero {
+            child.check_invariants(key.child(0))?;
+        }
+        if let Some(ref child) = self.one {
+            child.check_invariants(key.child(1))?;
+        }
+        Ok(())
+    }
+
+    fn covered_count(&self, key: Cidr) -> u128 {
+        if self.data.is_some() {
+            let (first, last) = key.span();
+            return (last - first).saturating_add(1);
+        }
+        let zero = self.zero.as_ref().map_or(0, |child| child.covered_count(key.child(0)));
+        let one = self.one.as_ref().map_or(0, |child| child.covered_count(key.child(1)));
+        zero.saturating_add(one)
+    }
+
+    // Finds the entry below this node (inclusive) with the lowest first address
+    // above ip, preferring the most specific on ties
+    fn next_after(&self, key: Cidr, ip: u128) -> Option<(Cidr, &T)> {
+        let (first, last) = key.span();
+        if last <= ip {
+            return None;
+        }
+        let below = self.zero.as_ref().and_then(|child| child.next_after(key.child(0), ip));
+        if below.is_some_and(|(cidr, _)| cidr.span().0 == first) {
+            return below;
+        }
+        match self.data {
+            Some(ref d) if first > ip => Some((key, d)),
+            _ => below.or_else(|| self.one.as_ref().and_then(|child| child.next_after(key.child(1), ip))),
+        }
+    }
+
+    // Finds the entry below this node (inclusive) with the highest last address
+    // below ip, preferring the most specific on ties
+    fn prev_before(&self, key: Cidr, ip: u128) -> Option<(Cidr, &T)> {
+        let (first, last) = key.span();
+        if first >= ip {
+            return None;
+        }
+        let below = self.one.as_ref().and_then(|child| child.prev_before(key.child(1), ip));
+        if below.is_some_and(|(cidr, _)| cidr.span().1 == last) {
+            return below;
+        }
+        match self.data {
+            Some(ref d) if last < ip => Some((key, d)),
+            _ => below.or_else(|| self.zero.as_ref().and_then(|child| child.prev_before(key.child(0), ip))),
+        }
+    }
+
+    // Removes the data of every node below this one (inclusive) that f
+    // rejects, returning how many were removed
+    fn retain<F>(&mut self, key: Cidr, f: &mut F) -> usize where F: FnMut(&Cidr, &T) -> bool {
+        let mut removed = 0;
+        if self.data.as_ref().is_some_and(|d| !f(&key, d)) {
+            self.data = None;
+            removed += 1;
+        }
+        for bit in 0..2 {
+            let child = match bit {
+                0 => &mut self.zero,
+                _ => &mut self.one,
+            };
+            if let Some(ref mut node) = *child {
+                removed += node.retain(key.child(bit), f);
+            }
+            if child.as_ref().is_some_and(|node| node.is_prunable()) {
+                *child = None;
+            }
+        }
+        removed
+    }
+
+    // Takes the node for the given CIDR out of the tree, pruning any branches
+    // left empty
+    fn detach(&mut self, cidr: &Cidr) -> Option<Node<T>> {
+        if cidr.length == 0 {
+            return Some(mem::replace(self, Node::new()));
+        }
+
+        let next_cidr = cidr.next();
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        let detached = match *child {
+            Some(ref mut node) => node.detach(&next_cidr),
+            None => None,
+        };
+        if child.as_ref().is_some_and(|node| node.is_prunable()) {
+            *child = None;
+        }
+        detached
+    }
+
+    // Merges another node and everything below it into this one. len must
+    // already include the other node's entries; it is decremented once for
+    // every CIDR both nodes have data for.
+    fn merge<F>(&mut self, other: Node<T>, key: Cidr, resolve: &mut F, len: &mut usize)
+        where F: FnMut(&Cidr, T, T) -> T
+    {
+        let Node { zero, one, data } = other;
+        self.data = match (self.data.take(), data) {
+            (Some(left), Some(right)) => {
+                *len -= 1;
+                Some(resolve(&key, left, right))
+            },
+            (left, right) => left.or(right),
+        };
+        for (bit, other_child) in [(0, zero), (1, one)] {
+            let child = match bit {
+                0 => &mut self.zero,
+                _ => &mut self.one,
+            };
+            match (child.as_mut(), other_child) {
+                (Some(node), Some(other_node)) => node.merge(*other_node, key.child(bit), resolve, len),
+                (None, other_child) => *child = other_child,
+                (Some(_), None) => {},
+            }
+        }
+    }
+
+    // Records the differences between two nodes, either of which may be missing,
+    // and everything below them
+    fn diff(old: Option<&Node<T>>, new: Option<&Node<T>>, key: Cidr, changes: &mut Changeset<T>)
+        where T: PartialEq + Clone
+    {
+        if old.is_none() && new.is_none() {
+            return;
+        }
+        match (old.and_then(|n| n.data.as_ref()), new.and_then(|n| n.data.as_ref())) {
+            (None, Some(d)) => changes.added.push((key, d.clone())),
+            (Some(d), None) => changes.removed.push((key, d.clone())),
+            (Some(o), Some(n)) if o != n => changes.changed.push((key, n.clone())),
+            _ => {},
+        }
+        for bit in 0..2 {
+            Node::diff(old.and_then(|n| n.child(bit)), new.and_then(|n| n.child(bit)), key.child(bit), changes);
+        }
+    }
+
+    // Puts the given node in place of the node for the given CIDR, creating the
+    // path to it as needed
+    fn attach(&mut self, cidr: &Cidr, node: Node<T>) {
+        if cidr.length == 0 {
+            *self = node;
+            return;
+        }
+        let next_cidr = cidr.next();
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        child.get_or_insert_with(|| Box::new(Node::new())).attach(&next_cidr, node)
+    }
+
+    // Returns the number of nodes below this one (inclusive) that hold data
+    fn count(&self) -> usize {
+        let below = self.zero.as_ref().map_or(0, |node| node.count()) +
+                    self.one.as_ref().map_or(0, |node| node.count());
+        below + self.data.is_some() as usize
+    }
+
+    fn find_mut(&mut self, cidr: &Cidr) -> Option<&mut Node<T>> {
+        match cidr.length {
+            0 => Some(self),
+            _ => {
+                let child = match cidr.msbit() {
+                    0 => self.zero.as_deref_mut(),
+                    _ => self.one.as_deref_mut(),
+                };
+                child.and_then(|child| child.find_mut(&cidr.next()))
+            },
+        }
+    }
+
+    fn child(&self, bit: u8) -> Option<&Node<T>> {
+        match bit {
+            0 => self.zero.as_deref(),
+            _ => self.one.as_deref(),
+        }
+    }
+
+    fn child_slot(&mut self, bit: u8) -> &mut Option<Box<Node<T>>> {
+        match bit {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.zero.is_none() && self.one.is_none()
+    }
+
+    // A node can be dropped when neither it nor any of its descendants hold data
+    fn is_prunable(&self) -> bool {
+        self.data.is_none() && self.is_leaf()
+    }
+}
+
+#[test]
+fn test_insert_v4() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+
+    assert!(t.get_from_str("1.0.0.0").is_empty());
+    assert!(t.get_from_str("128.0.0.0").len() == 1);
+    assert!(t.get_from_str("255.0.0.0").len() == 1);
+    assert!(t.get_from_str("128.0.0.0")[0] == "first");
+    assert!(t.get_from_str("128.1.0.0").len() == 1);
+    assert!(t.get_from_str("128.0.0.0/8").len() == 1);
+
+    t.insert(Cidr::from_str("255.0.0.0/2").unwrap(), "second".to_string());
+
+    assert!(t.get_from_str("1.0.0.0").is_empty());
+    assert!(t.get_from_str("128.0.0.0").len() == 1);
+    assert!(t.get_from_str("255.0.0.0").len() == 2);
+    assert!(t.get_from_str("128.0.0.0")[0] == "first");
+    assert!(t.get_from_str("128.1.0.0").len() == 1);
+    assert!(t.get_from_str("128.0.0.0/8").len() == 1);
+    assert!(t.get_from_str("255.0.0.0").len() == 2);
+    assert!(t.get_from_str("255.1.0.0").len() == 2);
+    assert!(t.get_from_str("255.0.0.0/8").len() == 2);
+}
+
+#[test]
+fn test_insert_v6() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("8000:0:0:0::/1").unwrap(), "first".to_string());
+    assert!(t.get_from_str("0001:0:0:0::").is_empty());
+    assert!(t.get_from_str("8000::").len() == 1);
+    assert!(t.get_from_str("F000::").len() == 1);
+    assert!(t.get_from_str("8000::1").len() == 1);
+    assert!(t.get_from_str("8000::/8").len() == 1);
+
+    t.insert(Cidr::from_str("F000:0:0:0::/2").unwrap(), "second".to_string());
+
+    assert!(t.get_from_str("0001:0:0:0::").is_empty());
+    assert!(t.get_from_str("8000::").len() == 1);
+    assert!(t.get_from_str("F000::").len() == 2);
+    assert!(t.get_from_str("8000::1").len() == 1);
+    assert!(t.get_from_str("8000::/8").len() == 1);
+    assert!(t.get_from_str("F000::").len() == 2);
+    assert!(t.get_from_str("F800::").len() == 2);
+    assert!(t.get_from_str("F000::/8").len() == 2);
+}
+
+#[test]
+fn test_remove() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+
+    assert!(t.remove(&Cidr::from_str("255.0.0.0/16").unwrap()).is_none());
+    assert!(t.remove(&Cidr::from_str("255.0.0.0/8").unwrap()) == Some("second".to_string()));
+    assert!(t.get_from_str("255.0.0.0").len() == 1);
+    assert!(t.remove(&Cidr::from_str("255.0.0.0/8").unwrap()).is_none());
+
+    // The branch below 128.0.0.0/1 held nothing else and should be gone
+    assert!(t.v4.one.as_ref().unwrap().one.is_none());
+
+    assert!(t.remove(&Cidr::from_str("128.0.0.0/1").unwrap()) == Some("first".to_string()));
+    assert!(t.get_from_str("255.0.0.0").is_empty());
+    assert!(t.v4.one.is_none());
+}
+
+#[test]
+fn test_longest_match() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+
+    assert!(t.longest_match(&Cidr::from_str("1.0.0.0").unwrap()).is_none());
+    assert!(t.longest_match(&Cidr::from_str("128.0.0.0").unwrap()).unwrap() == "first");
+    assert!(t.longest_match(&Cidr::from_str("255.1.0.0").unwrap()).unwrap() == "second");
+    assert!(t.longest_match(&Cidr::from_str("255.0.0.0/8").unwrap()).unwrap() == "second");
+
+    // Shorter queries only match entries covering the whole block
+    assert!(t.longest_match(&Cidr::from_str("255.0.0.0/1").unwrap()).unwrap() == "first");
+    assert!(t.get_from_str("255.0.0.0/1").len() == 1);
+}
+
+#[test]
+fn test_get_with_prefix() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+
+    let matches = t.get_with_prefix(&Cidr::from_str("255.1.2.3").unwrap());
+    assert!(matches.len() == 2);
+    assert!(matches[0] == (Cidr::from_str("128.0.0.0/1").unwrap(), &"first".to_string()));
+    assert!(matches[1] == (Cidr::from_str("255.0.0.0/8").unwrap(), &"second".to_string()));
+
+    let (cidr, data) = t.longest_match_with_prefix(&Cidr::from_str("128.0.0.1").unwrap()).unwrap();
+    assert!(cidr == Cidr::from_str("128.0.0.0/1").unwrap());
+    assert!(data == "first");
+    assert!(t.longest_match_with_prefix(&Cidr::from_str("1.0.0.0").unwrap()).is_none());
+
+    t.insert(Cidr::from_str("F000::/4").unwrap(), "third".to_string());
+    let (cidr, _) = t.longest_match_with_prefix(&Cidr::from_str("F000::1").unwrap()).unwrap();
+    assert!(cidr == Cidr::from_str("F000::/4").unwrap());
+}
+
+#[test]
+fn test_iter() {
+    let mut t = CidrTree::<String>::new();
+    assert!(t.iter().next().is_none());
+
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "v6".to_string());
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "zeroth".to_string());
+
+    let entries = t.iter().collect::<Vec<_>>();
+    assert!(entries.len() == 4);
+    assert!(entries[0] == (Cidr::from_str("10.0.0.0/8").unwrap(), &"zeroth".to_string()));
+    assert!(entries[1] == (Cidr::from_str("128.0.0.0/1").unwrap(), &"first".to_string()));
+    assert!(entries[2] == (Cidr::from_str("255.0.0.0/8").unwrap(), &"second".to_string()));
+    assert!(entries[3] == (Cidr::from_str("8000::/1").unwrap(), &"v6".to_string()));
+}
+
+#[test]
+fn test_families_are_separate() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "v4".to_string());
+    assert!(t.get_from_str("128.0.0.0").len() == 1);
+    assert!(t.get_from_str("8000::").is_empty());
+
+    let t = CidrTree::new_with_data("default".to_string());
+    assert!(t.get_from_str("1.2.3.4") == vec!["default"]);
+    assert!(t.get_from_str("::1") == vec!["default"]);
+}
+
+#[test]
+fn test_into_iter() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "v6".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+
+    let mut found = Vec::new();
+    for (cidr, value) in t {
+        found.push((cidr, value));
+    }
+    assert!(found == vec![(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string()),
+                          (Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string()),
+                          (Cidr::from_str("8000::/1").unwrap(), "v6".to_string())]);
+}
+
+#[test]
+fn test_from_iter_and_extend() {
+    let mut t = vec![(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string()),
+                     (Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string())]
+        .into_iter()
+        .collect::<CidrTree<String>>();
+    assert!(t.get_from_str("255.0.0.0") == vec!["first", "second"]);
+
+    t.extend(vec![(Cidr::from_str("255.0.0.0/8").unwrap(), "replaced".to_string()),
+                  (Cidr::from_str("8000::/1").unwrap(), "v6".to_string())]);
+    assert!(t.get_from_str("255.0.0.0") == vec!["first", "replaced"]);
+    assert!(t.get_from_str("8000::") == vec!["v6"]);
+}
+
+#[test]
+fn test_from_sorted() {
+    let entries = ["10.0.0.0/8", "10.1.0.0/16", "10.1.0.0/24", "10.1.0.0/24", "10.2.0.0/16",
+                   "192.168.0.0/16", "::/0", "2001:db8::/32"]
+        .iter().enumerate().map(|(i, s)| (Cidr::from_str(s).unwrap(), i))
+        .collect::<Vec<_>>();
+    let expected = entries.clone().into_iter().collect::<CidrTree<_>>();
+    let t = CidrTree::from_sorted(entries.clone());
+    assert!(t == expected && t.len() == 7);
+    assert!(t.get_exact(&Cidr::from_str("10.1.0.0/24").unwrap()) == Some(&3));
+    assert!(t.stats() == expected.stats());
+
+    // Unsorted input falls back to inserting
+    let mut shuffled = entries;
+    shuffled.reverse();
+    shuffled.swap(0, 5);
+    let t = CidrTree::from_sorted(shuffled.clone());
+    assert!(t == shuffled.into_iter().collect::<CidrTree<_>>());
+}
+
+#[test]
+fn test_non_debug_data() {
+    let mut t = CidrTree::<Box<dyn Fn(u32) -> u32>>::new();
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), Box::new(|x| x + 1));
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), Box::new(|x| x * 2));
+    let f = t.longest_match(&Cidr::from_str("10.1.2.3").unwrap()).unwrap();
+    assert!(f(5) == 10);
+}
+
+#[test]
+fn test_len() {
+    let mut t = CidrTree::<String>::new();
+    assert!(t.is_empty());
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "v6".to_string());
+    assert!(t.len() == 2);
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "replaced".to_string());
+    assert!(t.len() == 2);
+
+    t.remove(&Cidr::from_str("128.0.0.0/1").unwrap());
+    assert!(t.len() == 1);
+    assert!(t.remove(&Cidr::from_str("1.0.0.0/8").unwrap()).is_none());
+    assert!(t.len() == 1);
+
+    t.remove(&Cidr::from_str("8000::/1").unwrap());
+    assert!(t.is_empty());
+    assert!(CidrTree::new_with_data(0).len() == 2);
+}
+
+#[test]
+fn test_clear() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "v6".to_string());
+    t.clear();
+    assert!(t.is_empty());
+    assert!(t.iter().next().is_none());
+    assert!(t.get_from_str("128.0.0.0").is_empty());
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "again".to_string());
+    assert!(t.get_from_str("128.0.0.0") == vec!["again"]);
+}
+
+#[test]
+fn test_get_exact() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+
+    assert!(t.get_exact(&Cidr::from_str("128.0.0.0/1").unwrap()).unwrap() == "first");
+    assert!(t.get_exact(&Cidr::from_str("255.0.0.0/8").unwrap()).unwrap() == "second");
+    assert!(t.get_exact(&Cidr::from_str("255.0.0.0/16").unwrap()).is_none());
+
+    // Intermediate nodes exist but carry no data
+    assert!(t.get_exact(&Cidr::from_str("192.0.0.0/2").unwrap()).is_none());
+    assert!(!t.has_exact(Cidr::from_str("192.0.0.0/2").unwrap()));
+    assert!(t.has_exact(Cidr::from_str("128.0.0.0/1").unwrap()));
+}
+
+#[test]
+fn test_insert_returns_replaced() {
+    let mut t = CidrTree::<String>::new();
+
+    assert!(t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string()).is_none());
+    assert!(t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "second".to_string()) ==
+            Some("first".to_string()));
+    assert!(t.get_from_str("128.0.0.0") == vec!["second"]);
+}
+
+#[test]
+fn test_try_insert() {
+    let mut t = CidrTree::<String>::new();
+
+    assert!(t.try_insert(&Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string()).is_ok());
+    assert!(t.try_insert(&Cidr::from_str("128.0.0.0/2").unwrap(), "second".to_string()).is_ok());
+
+    let err = t.try_insert(&Cidr::from_str("128.0.0.0/1").unwrap(), "third".to_string()).unwrap_err();
+    assert!(err.cidr == Cidr::from_str("128.0.0.0/1").unwrap());
+    assert!(err.value == "third");
+    assert!(t.get_exact(&Cidr::from_str("128.0.0.0/1").unwrap()).unwrap() == "first");
+    assert!(t.len() == 2);
+}
+
+#[test]
+fn test_entry() {
+    let mut t = CidrTree::<u32>::new();
+    let cidr = Cidr::from_str("128.0.0.0/1").unwrap();
+
+    *t.entry(&cidr).or_insert(0) += 1;
+    *t.entry(&cidr).or_insert(0) += 1;
+    assert!(t.get_exact(&cidr) == Some(&2));
+    assert!(t.len() == 1);
+
+    t.entry(&Cidr::from_str("10.0.0.0/8").unwrap()).and_modify(|v| *v += 1).or_insert_with(|| 7);
+    t.entry(&Cidr::from_str("10.0.0.0/8").unwrap()).and_modify(|v| *v += 1).or_insert_with(|| 7);
+    assert!(t.get_from_str("10.1.2.3") == vec![&8]);
+    assert!(t.len() == 2);
+
+    *t.entry(&Cidr::from_str("8000::/1").unwrap()).or_default() += 5;
+    assert!(t.get_from_str("8000::") == vec![&5]);
+
+    match t.entry(&cidr) {
+        Entry::Occupied(mut entry) => {
+            assert!(*entry.key() == cidr);
+            assert!(entry.insert(10) == 2);
+        },
+        Entry::Vacant(_) => panic!("expected an occupied entry"),
+    }
+    assert!(t.get_exact(&cidr) == Some(&10));
+
+    // Keys are reported the way iter() yields them, with host bits cleared
+    let loose = Cidr::from_str("10.1.2.3/8").unwrap();
+    assert!(*t.entry(&loose).key() == Cidr::from_str("10.0.0.0/8").unwrap());
+    let loose = Cidr::from_str("192.168.1.1/16").unwrap();
+    assert!(*t.entry(&loose).key() == Cidr::from_str("192.168.0.0/16").unwrap());
+    t.entry(&loose).or_insert(1);
+    assert!(t.iter().any(|(cidr, _)| cidr == Cidr::from_str("192.168.0.0/16").unwrap()));
+}
+
+#[test]
+fn test_retain() {
+    let mut t = CidrTree::<u32>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), 1);
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), 2);
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 3);
+    t.insert(Cidr::from_str("8000::/1").unwrap(), 4);
+
+    t.retain(|cidr, v| *v != 2 && cidr.length != 8);
+    assert!(t.len() == 2);
+    assert!(t.iter().map(|(_, v)| *v).collect::<Vec<_>>() == vec![1, 4]);
+
+    // Only the branch leading to 128.0.0.0/1 should be left in the IPv4 trie
+    assert!(t.v4.zero.is_none());
+    assert!(t.v4.one.as_ref().unwrap().is_leaf());
+}
+
+#[test]
+fn test_remove_where() {
+    let mut t = CidrTree::<u32>::new();
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 1);
+    t.insert(Cidr::from_str("10.1.2.0/25").unwrap(), 2);
+    t.insert(Cidr::from_str("192.168.1.0/24").unwrap(), 3);
+    t.insert(Cidr::from_str("192.168.1.7/32").unwrap(), 4);
+
+    assert!(t.remove_where(|cidr| cidr.length > 24) == 2);
+    assert!(t.remove_where(|cidr| cidr.length > 24) == 0);
+    assert!(t.iter().map(|(_, v)| *v).collect::<Vec<_>>() == vec![1, 3]);
+
+    let supernets: CidrTree<()> = vec![(Cidr::from_str("192.168.0.0/16").unwrap(), ())]
+        .into_iter().collect();
+    assert!(t.remove_where(|cidr| supernets.covers(cidr)) == 1);
+    assert!(t.len() == 1);
+}
+
+#[test]
+fn test_drain() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "v6".to_string());
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+
+    let drained = t.drain().collect::<Vec<_>>();
+    assert!(drained == vec![(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string()),
+                            (Cidr::from_str("8000::/1").unwrap(), "v6".to_string())]);
+    assert!(t.is_empty());
+    assert!(t.get_from_str("128.0.0.0").is_empty());
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    drop(t.drain());
+    assert!(t.is_empty());
+}
+
+#[test]
+fn test_covered_by() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.1.2.0/24").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("11.0.0.0/8").unwrap(), "d".to_string());
+    t.insert(Cidr::from_str("a00::/8").unwrap(), "e".to_string());
+
+    let found = t.covered_by(&Cidr::from_str("10.0.0.0/8").unwrap()).map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+    assert!(found == vec!["a", "b", "c"]);
+
+    let found = t.covered_by(&Cidr::from_str("10.1.0.0/15").unwrap()).collect::<Vec<_>>();
+    assert!(found == vec![(Cidr::from_str("10.1.0.0/16").unwrap(), &"b".to_string()),
+                          (Cidr::from_str("10.1.2.0/24").unwrap(), &"c".to_string())]);
+
+    assert!(t.covered_by(&Cidr::from_str("10.2.0.0/16").unwrap()).next().is_none());
+    assert!(t.covered_by(&Cidr::from_str("0.0.0.0/0").unwrap()).count() == 4);
+}
+
+#[test]
+fn test_ancestors() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("0.0.0.0/0").unwrap(), "all".to_string());
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.1.2.0/24").unwrap(), "c".to_string());
+
+    let found = t.ancestors(&Cidr::from_str("10.1.2.3").unwrap()).collect::<Vec<_>>();
+    assert!(found == t.get_with_prefix(&Cidr::from_str("10.1.2.3").unwrap()));
+    assert!(found.len() == 4);
+    assert!(found[2] == (Cidr::from_str("10.1.0.0/16").unwrap(), &"b".to_string()));
+
+    let found = t.ancestors(&Cidr::from_str("10.1.0.0/16").unwrap()).map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+    assert!(found == vec!["all", "a", "b"]);
+    assert!(t.ancestors(&Cidr::from_str("::1").unwrap()).next().is_none());
+}
+
+#[test]
+fn test_remove_subtree() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("192.0.2.0/24").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("192.0.2.128/25").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("192.0.2.7").unwrap(), "d".to_string());
+
+    let removed = t.remove_subtree(&Cidr::from_str("192.0.2.0/24").unwrap()).collect::<Vec<_>>();
+    assert!(removed == vec![(Cidr::from_str("192.0.2.0/24").unwrap(), "b".to_string()),
+                            (Cidr::from_str("192.0.2.7/32").unwrap(), "d".to_string()),
+                            (Cidr::from_str("192.0.2.128/25").unwrap(), "c".to_string())]);
+    assert!(t.len() == 1);
+    assert!(t.get_from_str("192.0.2.7").is_empty());
+
+    // The branch that led to 192.0.2.0/24 is gone entirely
+    assert!(t.v4.one.is_none());
+
+    assert!(t.remove_subtree(&Cidr::from_str("192.0.2.0/24").unwrap()).next().is_none());
+    drop(t.remove_subtree(&Cidr::from_str("0.0.0.0/0").unwrap()));
+    assert!(t.is_empty());
+}
+
+#[test]
+fn test_contains_ip() {
+    let mut t = CidrTree::<u32>::with_match_policy(MatchPolicy::LongestMatch);
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 1);
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), 2);
+    t.insert(Cidr::from_str("2001:db8::/32").unwrap(), 3);
+
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert!(t.contains_ip(ip("10.200.0.1")));
+    assert!(t.covers(ip("10.200.0.1")) && t.covers(Cidr::try_from((ip("10.0.0.0"), 12)).unwrap()));
+    assert!(t.has_exact(Cidr::try_from((ip("10.1.0.0"), 16)).unwrap()) && t.get(ip("10.1.0.1")) == vec![&1, &2]);
+    assert!(!t.contains_ip(ip("11.0.0.1")));
+    assert!(t.contains_ip(ip("2001:db8::1")));
+    assert!(!t.contains_ip(ip("::ffff:10.0.0.1")));
+    assert!(t.lookup_ip(ip("10.1.2.3")) == vec![&2]);
+    assert!(t.lookup_ip(ip("2001:db8:ffff::")) == vec![&3]);
+    assert!(t.lookup_ip(ip("192.168.0.1")).is_empty());
+}
+
+#[test]
+fn test_overlaps() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "a".to_string());
+
+    assert!(t.covers(Cidr::from_str("10.1.2.0/24").unwrap()));
+    assert!(!t.covers(Cidr::from_str("10.0.0.0/8").unwrap()));
+
+    assert!(t.overlaps(&Cidr::from_str("10.1.2.0/24").unwrap()));
+    assert!(t.overlaps(&Cidr::from_str("10.1.0.0/16").unwrap()));
+    assert!(t.overlaps(&Cidr::from_str("10.0.0.0/8").unwrap()));
+    assert!(!t.overlaps(&Cidr::from_str("10.2.0.0/16").unwrap()));
+    assert!(!t.overlaps(&Cidr::from_str("a00::/8").unwrap()));
+}
+
+#[test]
+fn test_pop_most_specific() {
+    let mut t = CidrTree::<u32>::new();
+    let a = Cidr::from_str("10.0.0.0/8").unwrap();
+    let b = Cidr::from_str("10.1.0.0/16").unwrap();
+    t.insert(a, 1);
+    t.insert(b, 2);
+
+    let query = Cidr::from_str("10.1.2.3").unwrap();
+    assert!(t.pop_most_specific(&query) == Some((b, 2)));
+    assert!(t.pop_most_specific(&query) == Some((a, 1)));
+    assert!(t.pop_most_specific(&query).is_none());
+    assert!(t.is_empty() && t.check_invariants().is_ok());
+}
+
+#[test]
+fn test_shortest_match() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+
+    assert!(t.shortest_match(&Cidr::from_str("10.1.2.3").unwrap()).unwrap() == "a");
+    assert!(t.shortest_match_with_prefix(&Cidr::from_str("10.1.2.3").unwrap()) ==
+            Some((Cidr::from_str("10.0.0.0/8").unwrap(), &"a".to_string())));
+    assert!(t.shortest_match(&Cidr::from_str("11.0.0.0").unwrap()).is_none());
+}
+
+#[test]
+fn test_matches_desc() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("0.0.0.0/0").unwrap(), "global".to_string());
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "region".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "site".to_string());
+
+    let query = Cidr::from_str("10.1.2.3").unwrap();
+    let found = t.matches_desc(&query, 2).into_iter().map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+    assert!(found == vec!["site", "region"]);
+
+    let found = t.matches_desc(&query, 10);
+    assert!(found.len() == 3);
+    assert!(found[2] == (Cidr::from_str("0.0.0.0/0").unwrap(), &"global".to_string()));
+    assert!(t.matches_desc(&query, 0).is_empty());
+}
+
+#[test]
+fn test_lookup_many() {
+    let mut t = CidrTree::<u32>::new();
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 1);
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), 2);
+    t.insert(Cidr::from_str("10.1.2.0/24").unwrap(), 3);
+    t.insert(Cidr::from_str("2001:db8::/32").unwrap(), 4);
+
+    let queries = ["10.1.2.3", "10.1.3.3", "2001:db8::1", "10.1.2.0/23", "11.0.0.0",
+                   "10.0.0.1", "10.1.2.3", "::1", "0.0.0.0/0"]
+        .iter().map(|s| Cidr::from_str(s).unwrap()).collect::<Vec<_>>();
+    let expected = queries.iter().map(|q| t.longest_match(q)).collect::<Vec<_>>();
+    assert!(t.lookup_many(&queries) == expected);
+    assert!(expected[..4] == [Some(&3), Some(&2), Some(&4), Some(&2)]);
+    assert!(t.lookup_many(&[]).is_empty());
+}
+
+#[test]
+fn test_matches() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+
+    for query in &["1.0.0.0", "128.0.0.0", "255.1.0.0", "255.0.0.0/8", "255.0.0.0/1"] {
+        let cidr = Cidr::from_str(query).unwrap();
+        assert!(t.matches(&cidr).collect::<Vec<_>>() == t.get(cidr));
+    }
+}
+
+#[test]
+fn test_get_cloned() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("255.0.0.0/8").unwrap(), "second".to_string());
+
+    let query = Cidr::from_str("255.1.0.0").unwrap();
+    let owned = t.get_cloned(&query);
+    let longest = t.longest_match_cloned(&query);
+    drop(t);
+    assert!(owned == vec!["first".to_string(), "second".to_string()]);
+    assert!(longest == Some("second".to_string()));
+}
+
+#[test]
+fn test_walk() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("192.0.0.0/2").unwrap(), "second".to_string());
+    t.insert(Cidr::from_str("::/1").unwrap(), "v6".to_string());
+
+    let mut visited = Vec::new();
+    let flow = t.walk(WalkOrder::PreOrder, |cidr, depth, data| {
+        visited.push((*cidr, depth, data.cloned()));
+        ControlFlow::<()>::Continue(())
+    });
+    assert!(flow == ControlFlow::Continue(()));
+    assert!(visited == vec![(Cidr::from_str("0.0.0.0/0").unwrap(), 0, None),
+                            (Cidr::from_str("128.0.0.0/1").unwrap(), 1, Some("first".to_string())),
+                            (Cidr::from_str("192.0.0.0/2").unwrap(), 2, Some("second".to_string())),
+                            (Cidr::from_str("::/0").unwrap(), 0, None),
+                            (Cidr::from_str("::/1").unwrap(), 1, Some("v6".to_string()))]);
+
+    let mut depths = Vec::new();
+    let _ = t.walk(WalkOrder::PostOrder, |_, depth, _| {
+        depths.push(depth);
+        ControlFlow::<()>::Continue(())
+    });
+    assert!(depths == vec![2, 1, 0, 1, 0]);
+
+    // Stop at the first node deeper than /1
+    let flow = t.walk(WalkOrder::PreOrder, |cidr, depth, _| {
+        if depth > 1 { ControlFlow::Break(*cidr) } else { ControlFlow::Continue(()) }
+    });
+    assert!(flow == ControlFlow::Break(Cidr::from_str("192.0.0.0/2").unwrap()));
+}
+
+#[test]
+fn test_walk_mut() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("128.0.0.0/1").unwrap(), "first".to_string());
+    t.insert(Cidr::from_str("192.0.0.0/2").unwrap(), "second".to_string());
+    t.insert(Cidr::from_str("::/1").unwrap(), "v6".to_string());
+
+    // Re-tag everything, drop the /2 and give the IPv4 root some data
+    let _ = t.walk_mut(WalkOrder::PreOrder, |cidr, depth, data| {
+        if depth == 2 {
+            *data = None;
+        } else if let Some(ref mut d) = *data {
+            d.push_str("-tagged");
+        } else if *cidr == Cidr::from_str("0.0.0.0/0").unwrap() {
+            *data = Some("default".to_string());
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    assert!(t.len() == 3);
+    assert!(t.get_from_str("192.0.0.0") == vec!["default", "first-tagged"]);
+    assert!(t.get_from_str("::1") == vec!["v6-tagged"]);
+    assert!(t.v4.one.as_ref().unwrap().is_leaf());
+
+    // Breaking out early still leaves the tree consistent
+    let flow = t.walk_mut(WalkOrder::PostOrder, |_, _, data| {
+        *data = None;
+        ControlFlow::Break(())
+    });
+    assert!(flow == ControlFlow::Break(()));
+    assert!(t.len() == 2);
+    assert!(t.v4.one.is_none());
+}
+
+#[test]
+fn test_split_off() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("172.16.0.0/12").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("172.16.1.0/24").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "d".to_string());
+
+    let split = t.split_off(&Cidr::from_str("172.16.0.0/12").unwrap());
+    assert!(split.len() == 2);
+    assert!(split.iter().collect::<Vec<_>>() ==
+            vec![(Cidr::from_str("172.16.0.0/12").unwrap(), &"b".to_string()),
+                 (Cidr::from_str("172.16.1.0/24").unwrap(), &"c".to_string())]);
+    assert!(split.get_from_str("172.16.1.1") == vec!["b", "c"]);
+
+    assert!(t.len() == 2);
+    assert!(t.get_from_str("172.16.1.1").is_empty());
+    assert!(t.get_from_str("10.1.1.1") == vec!["a"]);
+
+    assert!(t.split_off(&Cidr::from_str("192.168.0.0/16").unwrap()).is_empty());
+}
+
+#[test]
+fn test_merge() {
+    let mut left = CidrTree::<String>::new();
+    left.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    left.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+
+    let mut right = CidrTree::<String>::new();
+    right.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "c".to_string());
+    right.insert(Cidr::from_str("10.1.2.0/24").unwrap(), "d".to_string());
+    right.insert(Cidr::from_str("8000::/1").unwrap(), "e".to_string());
+
+    let mut conflicts = Vec::new();
+    let merged = left.merge(right, |cidr, l, r| {
+        conflicts.push(*cidr);
+        l + &r
+    });
+    assert!(conflicts == vec![Cidr::from_str("10.1.0.0/16").unwrap()]);
+    assert!(merged.len() == 4);
+    assert!(merged.get_from_str("10.1.2.3") == vec!["a", "bc", "d"]);
+    assert!(merged.get_from_str("8000::") == vec!["e"]);
+}
+
+#[test]
+fn test_diff() {
+    let mut old = CidrTree::<String>::new();
+    old.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    old.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    old.insert(Cidr::from_str("8000::/1").unwrap(), "c".to_string());
+
+    let mut new = CidrTree::<String>::new();
+    new.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    new.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "changed".to_string());
+    new.insert(Cidr::from_str("10.1.2.0/24").unwrap(), "d".to_string());
+
+    let changes = old.diff(&new);
+    assert!(changes.added == vec![(Cidr::from_str("10.1.2.0/24").unwrap(), "d".to_string())]);
+    assert!(changes.removed == vec![(Cidr::from_str("8000::/1").unwrap(), "c".to_string())]);
+    assert!(changes.changed == vec![(Cidr::from_str("10.1.0.0/16").unwrap(), "changed".to_string())]);
+
+    assert!(old.diff(&old).is_empty());
+}
+
+#[test]
+fn test_apply_changeset() {
+    let mut old = CidrTree::<String>::new();
+    old.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    old.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    old.insert(Cidr::from_str("8000::/1").unwrap(), "c".to_string());
+
+    let mut new = CidrTree::<String>::new();
+    new.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "changed".to_string());
+    new.insert(Cidr::from_str("10.1.2.0/24").unwrap(), "d".to_string());
+
+    let mut replica = old.iter().map(|(cidr, v)| (cidr, v.clone())).collect::<CidrTree<_>>();
+    replica.apply_changeset(old.diff(&new));
+    assert!(replica.diff(&new).is_empty());
+    assert!(replica.len() == 2);
+    assert!(replica.get_from_str("8000::").is_empty());
+}
+
+#[test]
+fn test_eq() {
+    let mut a = CidrTree::<String>::new();
+    a.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    a.insert(Cidr::from_str("8000::/1").unwrap(), "b".to_string());
+
+    let mut b = CidrTree::<String>::new();
+    b.insert(Cidr::from_str("8000::/1").unwrap(), "b".to_string());
+    b.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "x".to_string());
+    b.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    assert!(a != b);
+
+    b.remove(&Cidr::from_str("10.1.0.0/16").unwrap());
+    assert!(a == b);
+
+    b.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "changed".to_string());
+    assert!(a != b);
+    assert!(CidrTree::<String>::new() == CidrTree::new());
+}
+
+#[test]
+fn test_clone() {
+    let mut a = CidrTree::<String>::new();
+    a.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+
+    let mut b = a.clone();
+    assert!(a == b);
+
+    b.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    assert!(a.len() == 1);
+    assert!(a.get_from_str("10.1.0.0") == vec!["a"]);
+    assert!(b.get_from_str("10.1.0.0") == vec!["a", "b"]);
+}
+
+#[test]
+fn test_fmt() {
+    let mut t = CidrTree::<String>::new();
+    assert!(format!("{:?}", t) == "{}");
+    assert!(format!("{}", t) == "");
+
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.2.0.0/16").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("2001:db8::/32").unwrap(), "d".to_string());
+
+    assert!(format!("{:?}", t) ==
+            "{10.0.0.0/8: \"a\", 10.1.0.0/16: \"b\", 10.2.0.0/16: \"c\", 2001:db8::/32: \"d\"}");
+    assert!(format!("{}", t) ==
+            "10.0.0.0/8 -> a\n10.1.0.0/16 -> b\n10.2.0.0/16 -> c\n2001:db8::/32 -> d\n");
+    assert!(format!("{:#}", t) ==
+            "10.0.0.0/8 -> a\n  10.1.0.0/16 -> b\n  10.2.0.0/16 -> c\n2001:db8::/32 -> d\n");
+}
+
+#[test]
+fn test_range() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("0.0.0.0/0").unwrap(), "all".to_string());
+    t.insert(Cidr::from_str("10.0.0.0/16").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.2.0.0/15").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("10.4.0.0/16").unwrap(), "d".to_string());
+    t.insert(Cidr::from_str("::/0").unwrap(), "v6".to_string());
+
+    let start = "10.1.0.0".parse::<IpAddr>().unwrap();
+    let end = "10.3.255.255".parse::<IpAddr>().unwrap();
+    let found = t.range(start..=end).map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+    assert!(found == vec!["all", "b", "c"]);
+
+    let found = t.range(start..=start).collect::<Vec<_>>();
+    assert!(found[1] == (Cidr::from_str("10.1.0.0/16").unwrap(), &"b".to_string()));
+
+    let v6 = "::1".parse::<IpAddr>().unwrap();
+    assert!(t.range(start..=v6).next().is_none());
+    assert!(t.range(end..=start).next().is_none());
+    assert!(t.range(v6..=v6).count() == 1);
+}
+
+#[test]
+fn test_next_prev_covered() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/24").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("10.255.0.0/16").unwrap(), "d".to_string());
+    t.insert(Cidr::from_str("192.168.0.0/16").unwrap(), "e".to_string());
+
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    let name = |found: Option<(Cidr, &String)>| found.map(|(_, v)| v.clone());
+
+    assert!(name(t.next_covered_after(ip("9.0.0.0"))) == Some("a".to_string()));
+    assert!(name(t.next_covered_after(ip("10.0.0.0"))) == Some("c".to_string()));
+    assert!(name(t.next_covered_after(ip("10.1.0.0"))) == Some("d".to_string()));
+    assert!(name(t.next_covered_after(ip("10.255.0.0"))) == Some("e".to_string()));
+    assert!(name(t.next_covered_after(ip("192.168.0.0"))).is_none());
+    assert!(t.next_covered_after(ip("10.0.0.0")).unwrap().0 == Cidr::from_str("10.1.0.0/24").unwrap());
+
+    assert!(name(t.prev_covered_before(ip("10.2.0.0"))) == Some("b".to_string()));
+    assert!(name(t.prev_covered_before(ip("10.1.1.0"))) == Some("c".to_string()));
+    assert!(name(t.prev_covered_before(ip("10.1.0.255"))).is_none());
+    assert!(name(t.prev_covered_before(ip("10.0.0.0"))).is_none());
+    assert!(t.next_covered_after(ip("::")).is_none());
+}
+
+#[test]
+fn test_gaps() {
+    let mut t = CidrTree::<String>::new();
+
+    let universe = Cidr::from_str("10.0.0.0/8").unwrap();
+    assert!(t.gaps(&universe).collect::<Vec<_>>() == vec![universe]);
+
+    t.insert(Cidr::from_str("10.0.0.0/10").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.128.0.0/9").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.96.0.0/11").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("10.64.1.0/24").unwrap(), "d".to_string());
+
+    let gaps = t.gaps(&universe).collect::<Vec<_>>();
+    assert!(gaps == vec![Cidr::from_str("10.64.0.0/24").unwrap(),
+                         Cidr::from_str("10.64.2.0/23").unwrap(),
+                         Cidr::from_str("10.64.4.0/22").unwrap(),
+                         Cidr::from_str("10.64.8.0/21").unwrap(),
+                         Cidr::from_str("10.64.16.0/20").unwrap(),
+                         Cidr::from_str("10.64.32.0/19").unwrap(),
+                         Cidr::from_str("10.64.64.0/18").unwrap(),
+                         Cidr::from_str("10.64.128.0/17").unwrap(),
+                         Cidr::from_str("10.65.0.0/16").unwrap(),
+                         Cidr::from_str("10.66.0.0/15").unwrap(),
+                         Cidr::from_str("10.68.0.0/14").unwrap(),
+                         Cidr::from_str("10.72.0.0/13").unwrap(),
+                         Cidr::from_str("10.80.0.0/12").unwrap()]);
+
+    assert!(t.gaps(&Cidr::from_str("10.1.0.0/16").unwrap()).next().is_none());
+    assert!(t.gaps(&Cidr::from_str("10.64.1.0/25").unwrap()).next().is_none());
+    assert!(t.gaps(&Cidr::from_str("::/0").unwrap()).collect::<Vec<_>>() ==
+            vec![Cidr::from_str("::/0").unwrap()]);
+}
+
+#[test]
+fn test_covered_count() {
+    let mut t = CidrTree::<String>::new();
+    assert!(t.covered_count() == 0);
+
+    t.insert(Cidr::from_str("10.0.0.0/24").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.0.0.128/25").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.0.1.1").unwrap(), "c".to_string());
+    assert!(t.covered_count() == 257);
+
+    t.insert(Cidr::from_str("2001:db8::/64").unwrap(), "d".to_string());
+    assert!(t.covered_count() == 257 + (1 << 64));
+
+    t.insert(Cidr::from_str("::/0").unwrap(), "e".to_string());
+    assert!(t.covered_count() == u128::MAX);
+}
+
+#[test]
+fn test_find_first_uncovered() {
+    let mut t = CidrTree::<String>::new();
+
+    let pool = Cidr::from_str("192.168.0.0/24").unwrap();
+    assert!(t.find_first_uncovered(&pool) == Some("192.168.0.0".parse().unwrap()));
+
+    t.insert(Cidr::from_str("192.168.0.0/31").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("192.168.0.2").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("192.168.0.4").unwrap(), "c".to_string());
+    assert!(t.find_first_uncovered(&pool) == Some("192.168.0.3".parse().unwrap()));
+
+    t.insert(Cidr::from_str("192.168.0.0/24").unwrap(), "all".to_string());
+    assert!(t.find_first_uncovered(&pool).is_none());
+}
+
+#[test]
+fn test_stats() {
+    let mut t = CidrTree::<String>::new();
+    assert!(t.stats() == TreeStats {
+        max_depth: 0,
+        node_count: 2,
+        data_node_count: 0,
+        leaf_count: 2,
+        one_child_count: 0,
+        two_children_count: 0,
+    });
+
+    t.insert(Cidr::from_str("128.0.0.0/2").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("192.0.0.0/2").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("::/4").unwrap(), "c".to_string());
+    assert!(t.stats() == TreeStats {
+        max_depth: 4,
+        node_count: 9,
+        data_node_count: 3,
+        leaf_count: 3,
+        one_child_count: 5,
+        two_children_count: 1,
+    });
+}
+
+#[test]
+fn test_check_invariants() {
+    let mut t = CidrTree::<u32>::new();
+    assert!(t.check_invariants().is_ok());
+
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), 1);
+    t.insert(Cidr::from_str("10.1.2.3/32").unwrap(), 2);
+    t.insert(Cidr::from_str("::1/128").unwrap(), 3);
+    t.remove(&Cidr::from_str("10.1.2.3/32").unwrap());
+    assert!(t.check_invariants().is_ok());
+
+    t.len += 1;
+    assert!(t.check_invariants() == Err(InvariantError::LenMismatch { len: 3, counted: 2 }));
+    t.len -= 1;
+
+    t.v4.zero.as_mut().unwrap().one = Some(Box::new(Node::new()));
+    let empty = Cidr::from_str("64.0.0.0/2").unwrap();
+    assert!(t.check_invariants() == Err(InvariantError::EmptyLeaf(empty)));
+    t.v4.zero.as_mut().unwrap().one = None;
+
+    let host = Cidr::from_str("::1/128").unwrap();
+    t.v6.find_mut(&host).unwrap().zero = Some(Box::new(Node::new_with_data(4)));
+    assert!(t.check_invariants() == Err(InvariantError::TooDeep(host)));
+}
+
+#[test]
+fn test_compact() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/26").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.0.0.64/26").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.0.0.128/25").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.0.1.0/25").unwrap(), "a".to_string());
+    t.insert(Cidr::from_str("10.0.1.128/25").unwrap(), "b".to_string());
+    t.insert(Cidr::from_str("10.0.2.0/25").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("10.0.2.128/25").unwrap(), "c".to_string());
+    t.insert(Cidr::from_str("10.0.2.0/24").unwrap(), "parent".to_string());
+
+    t.compact();
+    assert!(t.len() == 6);
+    assert!(t.iter().map(|(cidr, v)| (cidr, v.as_str())).collect::<Vec<_>>() ==
+            vec![(Cidr::from_str("10.0.0.0/24").unwrap(), "a"),
+                 (Cidr::from_str("10.0.1.0/25").unwrap(), "a"),
+                 (Cidr::from_str("10.0.1.128/25").unwrap(), "b"),
+                 (Cidr::from_str("10.0.2.0/24").unwrap(), "parent"),
+                 (Cidr::from_str("10.0.2.0/25").unwrap(), "c"),
+                 (Cidr::from_str("10.0.2.128/25").unwrap(), "c")]);
+}
+
+#[test]
+fn test_minimal_cover() {
+    let mut t = CidrTree::<&str>::new();
+    assert!(t.minimal_cover().is_empty());
+
+    t.insert(Cidr::from_str("10.0.0.0/25").unwrap(), "a");
+    t.insert(Cidr::from_str("10.0.0.128/26").unwrap(), "b");
+    t.insert(Cidr::from_str("10.0.0.192/26").unwrap(), "a");
+    t.insert(Cidr::from_str("10.0.0.200/32").unwrap(), "b");
+    t.insert(Cidr::from_str("10.0.2.0/24").unwrap(), "a");
+    t.insert(Cidr::from_str("8000::/1").unwrap(), "b");
+    t.insert(Cidr::from_str("::/1").unwrap(), "b");
+
+    let cidrs = |v: &[&str]| v.iter().map(|s| Cidr::from_str(s).unwrap()).collect::<Vec<_>>();
+    assert!(t.minimal_cover() == cidrs(&["10.0.0.0/24", "10.0.2.0/24", "::/0"]));
+
+    let by_value = t.minimal_cover_by_value();
+    assert!(by_value.iter().map(|&(c, _)| c).collect::<Vec<_>>() ==
+            cidrs(&["10.0.0.0/25", "10.0.0.192/26", "10.0.2.0/24",
+                    "10.0.0.128/26", "10.0.0.200/32", "::/0"]));
+    assert!(by_value.iter().map(|&(_, v)| *v).collect::<Vec<_>>() ==
+            vec!["a", "a", "a", "b", "b", "b"]);
+}
+
+#[test]
+fn test_dedup_covered() {
+    let mut t = CidrTree::<String>::new();
+
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "block".to_string());
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "block".to_string());
+    t.insert(Cidr::from_str("10.1.2.0/24").unwrap(), "block".to_string());
+    t.insert(Cidr::from_str("10.2.0.0/16").unwrap(), "allow".to_string());
+    t.insert(Cidr::from_str("10.2.3.0/24").unwrap(), "block".to_string());
+    t.insert(Cidr::from_str("10.2.3.4").unwrap(), "block".to_string());
+
+    assert!(t.dedup_covered() == 3);
+    assert!(t.len() == 3);
+    assert!(t.iter().map(|(cidr, v)| (cidr, v.as_str())).collect::<Vec<_>>() ==
+            vec![(Cidr::from_str("10.0.0.0/8").unwrap(), "block"),
+                 (Cidr::from_str("10.2.0.0/16").unwrap(), "allow"),
+                 (Cidr::from_str("10.2.3.0/24").unwrap(), "block")]);
+    assert!(t.get_exact(&Cidr::from_str("10.1.0.0/16").unwrap()).is_none());
+    assert!(t.v4.find(&Cidr::from_str("10.1.0.0/16").unwrap()).is_none());
+}
+
+#[test]
+fn test_insert_with_policy() {
+    let mut t = CidrTree::<u32>::new();
+    let cidr = Cidr::from_str("10.0.0.0/8").unwrap();
+
+    assert!(t.insert_with_policy(&cidr, 1, InsertPolicy::KeepExisting).unwrap().is_none());
+    assert!(t.insert_with_policy(&cidr, 2, InsertPolicy::KeepExisting).unwrap() == Some(2));
+    assert!(t.get_exact(&cidr) == Some(&1));
+
+    assert!(t.insert_with_policy(&cidr, 3, InsertPolicy::Replace).unwrap() == Some(1));
+    assert!(t.get_exact(&cidr) == Some(&3));
+
+    let err = t.insert_with_policy(&cidr, 4, InsertPolicy::Error).unwrap_err();
+    assert!(err.value == 4);
+    assert!(t.get_exact(&cidr) == Some(&3));
+
+    let sum = InsertPolicy::Merge(|_, existing, new| existing + new);
+    assert!(t.insert_with_policy(&cidr, 5, sum).unwrap().is_none());
+    assert!(t.get_exact(&cidr) == Some(&8));
+
+    let other = Cidr::from_str("10.1.0.0/16").unwrap();
+    assert!(t.insert_with_policy(&other, 5, sum).unwrap().is_none());
+    assert!(t.get_exact(&other) == Some(&5));
+    assert!(t.len() == 2);
+}
+
+#[test]
+fn test_fold_matches() {
+    let mut t = CidrTree::<Vec<(&str, u32)>>::new();
+
+    t.insert(Cidr::from_str("0.0.0.0/0").unwrap(), vec![("mtu", 1500), ("ttl", 64)]);
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), vec![("mtu", 9000)]);
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), vec![("ttl", 32)]);
+
+    // Layer the options, more specific CIDRs overriding broader ones
+    let options = t.fold_matches(&Cidr::from_str("10.1.2.3").unwrap(), Vec::new(), |mut acc, opts| {
+        for &(name, value) in opts {
+            acc.retain(|&(n, _)| n != name);
+            acc.push((name, value));
+        }
+        acc
+    });
+    assert!(options == vec![("mtu", 9000), ("ttl", 32)]);
+
+    let count = t.fold_matches(&Cidr::from_str("11.0.0.0").unwrap(), 0, |acc, _| acc + 1);
+    assert!(count == 1);
+}
+
+#[test]
+fn test_lookup() {
+    let build = |policy| {
+        let mut t = CidrTree::<String>::with_match_policy(policy);
+        t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), "a".to_string());
+        t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), "b".to_string());
+        t
+    };
+    let query = Cidr::from_str("10.1.2.3").unwrap();
+
+    assert!(build(MatchPolicy::FirstMatch).lookup(&query) == vec!["a"]);
+    assert!(build(MatchPolicy::LongestMatch).lookup(&query) == vec!["b"]);
+    assert!(build(MatchPolicy::AllMatches).lookup(&query) == vec!["a", "b"]);
+    assert!(build(MatchPolicy::LongestMatch).lookup(&Cidr::from_str("11.0.0.0").unwrap()).is_empty());
+
+    let mut t = build(MatchPolicy::LongestMatch);
+    assert!(t.split_off(&Cidr::from_str("10.1.0.0/16").unwrap()).match_policy() == MatchPolicy::LongestMatch);
+    t.drain();
+    assert!(t.match_policy() == MatchPolicy::LongestMatch);
+    t.set_match_policy(MatchPolicy::FirstMatch);
+    assert!(t.match_policy() == MatchPolicy::FirstMatch);
+}
+
+#[test]
+fn test_cursor() {
+    let mut t = CidrTree::<u32>::new();
+    let a = Cidr::from_str("128.0.0.0/1").unwrap();
+    let b = Cidr::from_str("192.0.0.0/2").unwrap();
+    t.insert(a, 1);
+    t.insert(b, 2);
+
+    let root = Cidr::from_str("0.0.0.0/0").unwrap();
+    let mut c = t.cursor(&root).unwrap();
+    assert!(c.data().is_none());
+    assert!(!c.has_child(0) && !c.go_zero());
+    assert!(c.go_one() && c.key() == a && c.data() == Some(&1));
+    assert!(c.go_one() && c.key() == b && c.data() == Some(&2));
+    assert!(!c.go_zero() && !c.go_one());
+    assert!(c.up() && c.up() && c.key() == root);
+    assert!(!c.up());
+    assert!(t.cursor(&Cidr::from_str("10.0.0.0/8").unwrap()).is_none());
+
+    let mut c = t.cursor_mut(&a);
+    assert!(c.data() == Some(&1));
+    *c.data_mut().unwrap() = 10;
+    assert!(c.go_zero() && c.key() == Cidr::from_str("128.0.0.0/2").unwrap());
+    assert!(!c.has_child(0) && c.data().is_none());
+    assert!(c.insert(3).is_none());
+    assert!(c.up() && c.go_one() && c.remove() == Some(2));
+    assert!(t.iter().collect::<Vec<_>>() ==
+            vec![(a, &10), (Cidr::from_str("128.0.0.0/2").unwrap(), &3)]);
+
+    let mut c = t.cursor_mut(&Cidr::from_str("1.2.3.4/32").unwrap());
+    assert!(!c.go_one());
+}
+
+#[test]
+fn test_cursor_mut_walk() {
+    let mut t = CidrTree::<u32>::new();
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 1);
+
+    // Walk down bit by bit into CIDRs the tree has no nodes for, insert, and
+    // come back up past the new nodes
+    let mut c = t.cursor_mut(&Cidr::from_str("0.0.0.0/0").unwrap());
+    for i in 0..24 {
+        assert!(c.go_child(Cidr::from_str("10.1.2.0/24").unwrap().prefix.bit(i)));
+        assert!(c.data() == if i == 7 { Some(&1) } else { None });
+    }
+    assert!(c.insert(2).is_none() && c.insert(3) == Some(2));
+    assert!(c.go_one() && c.insert(4).is_none());
+    for _ in 0..17 {
+        assert!(c.up());
+    }
+    assert!(c.key() == Cidr::from_str("10.0.0.0/8").unwrap() && c.data() == Some(&1));
+    assert!(c.has_child(0) && !c.has_child(1));
+    *c.data_mut().unwrap() += 10;
+    assert!(t.len() == 3 && t.check_invariants().is_ok());
+    assert!(t.get_from_str("10.1.2.128") == vec![&11, &3, &4]);
+
+    // Removing prunes the emptied nodes but leaves the cursor in place
+    let mut c = t.cursor_mut(&Cidr::from_str("10.1.2.128/25").unwrap());
+    assert!(c.remove() == Some(4) && c.remove().is_none());
+    assert!(c.up() && c.data() == Some(&3) && !c.has_child(1));
+    assert!(c.remove() == Some(3));
+    assert!(c.key() == Cidr::from_str("10.1.2.0/24").unwrap() && c.data().is_none());
+    assert!(t.len() == 1 && t.check_invariants().is_ok());
+    let mut fresh = CidrTree::new();
+    fresh.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 11);
+    assert!(t.stats() == fresh.stats());
+}
+
+// CursorMut is the crate's only unsafe code; check it with
+// `cargo +nightly miri test cursor_mut`, which runs this and the test above
+#[test]
+fn test_cursor_mut_prune_and_regrow() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    let mut t = CidrTree::<u32>::new();
+    t.insert(c("10.0.0.0/8"), 1);
+    t.insert(c("2001:db8::/32"), 2);
+
+    // Insert deep, mutate an ancestor while pointers to its descendants are
+    // still on the path, then read the descendants through the path again
+    let mut cur = t.cursor_mut(&c("10.0.0.0/8"));
+    for _ in 0..16 {
+        assert!(cur.go_zero());
+    }
+    assert!(cur.insert(3).is_none());
+    assert!(cur.go_one() && cur.insert(4).is_none() && cur.up());
+    for _ in 0..16 {
+        assert!(cur.up());
+    }
+    *cur.data_mut().unwrap() += 10;
+    for _ in 0..16 {
+        assert!(cur.go_zero());
+    }
+    assert!(cur.data() == Some(&3) && cur.has_child(1));
+
+    // Prune everything below 10.0.0.0/8, then grow a path back through the
+    // same slots and prune it again from the bottom
+    assert!(cur.go_one() && cur.remove() == Some(4) && cur.up());
+    assert!(cur.remove() == Some(3) && !cur.has_child(0) && !cur.has_child(1));
+    assert!(cur.go_one() && cur.insert(5).is_none() && cur.remove() == Some(5));
+    for _ in 0..17 {
+        assert!(cur.up());
+    }
+    assert!(cur.data() == Some(&11) && !cur.has_child(0));
+    assert!(cur.remove() == Some(11));
+    assert!(t.len() == 1 && t.check_invariants().is_ok());
+    assert!(t.iter().collect::<Vec<_>>() == vec![(c("2001:db8::/32"), &2)]);
+    assert!(t.stats() == vec![(c("2001:db8::/32"), 2)].into_iter().collect::<CidrTree<_>>().stats());
+}
+
+#[test]
+fn test_at() {
+    let mut t = CidrTree::<u32>::new();
+    let block = Cidr::from_str("172.16.0.0/12").unwrap();
+    let inner = Cidr::from_str("172.16.1.0/24").unwrap();
+    t.insert(Cidr::from_str("172.0.0.0/8").unwrap(), 1);
+    t.insert(block, 2);
+    t.insert(inner, 3);
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 4);
 
-    t.insert(&Cidr::from_str("F000:0:0:0::/2").unwrap(), Some("second".to_string()));
+    let view = t.at(&block).unwrap();
+    assert!(view.key() == block && view.len() == 2);
+    assert!(view.get(&Cidr::from_str("172.16.1.1").unwrap()) == vec![&2, &3]);
+    assert!(view.longest_match(&Cidr::from_str("172.17.0.1").unwrap()) == Some(&2));
+    assert!(view.get_exact(&inner) == Some(&3));
+    assert!(view.get(&Cidr::from_str("10.0.0.1").unwrap()).is_empty());
+    assert!(view.iter().map(|(_, v)| *v).collect::<Vec<_>>() == vec![2, 3]);
+    assert!(t.at(&Cidr::from_str("192.168.0.0/16").unwrap()).is_none());
 
-    assert!(t.get_from_str(&"0001:0:0:0::").is_empty());
-    assert!(t.get_from_str(&"8000::").len() == 1);
-    assert!(t.get_from_str(&"F000::").len() == 2);
-    assert!(t.get_from_str(&"8000::1").len() == 1);
-    assert!(t.get_from_str(&"8000::/8").len() == 1);
-    assert!(t.get_from_str(&"F000::").len() == 2);
-    assert!(t.get_from_str(&"F800::").len() == 2);
-    assert!(t.get_from_str(&"F000::/8").len() == 2);
+    let mut view = t.at_mut(&block);
+    *view.get_exact_mut(&inner).unwrap() = 30;
+    assert!(view.get_exact_mut(&Cidr::from_str("10.0.0.0/8").unwrap()).is_none());
+    assert!(view.insert(&Cidr::from_str("172.20.0.0/16").unwrap(), 5).is_none());
+    assert!(view.remove(&Cidr::from_str("172.0.0.0/8").unwrap()).is_none());
+    assert!(view.get_exact(&inner) == Some(&30) && view.len() == 3);
+    view.clear();
+    assert!(view.is_empty());
+    assert!(t.len() == 2);
 }
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+
+// A CidrTree that counts how many lookups each stored CIDR has matched, e.g. to
+// find firewall rules that never fire. Counting works through a shared
+// reference, so a tree behind an Arc can be looked up from several threads.
+pub struct CountingCidrTree<T> {
+    tree: CidrTree<Counted<T>>,
+}
+
+#[derive(Debug)]
+struct Counted<T> {
+    data: T,
+    hits: AtomicU64,
+}
+
+impl<T> Counted<T> {
+    // Records a hit and hands back the data
+    fn hit(&self) -> &T {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        &self.data
+    }
+}
+
+impl<T> Default for CountingCidrTree<T> {
+    fn default() -> CountingCidrTree<T> {
+        CountingCidrTree::new()
+    }
+}
+
+impl<T> CountingCidrTree<T> {
+    pub fn new() -> CountingCidrTree<T> {
+        CountingCidrTree {
+            tree: CidrTree::new(),
+        }
+    }
+
+    // Stores data at the given CIDR and returns the data it replaced, if any.
+    // The CIDR's hit count starts again from zero.
+    pub fn insert(&mut self, cidr: &Cidr, data: T) -> Option<T> {
+        let counted = Counted {
+            data,
+            hits: AtomicU64::new(0),
+        };
+        self.tree.insert(cidr, counted).map(|old| old.data)
+    }
+
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        self.tree.remove(cidr).map(|old| old.data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // Like CidrTree::get(), counting a hit for every CIDR matched
+    pub fn get(&self, cidr: &Cidr) -> Vec<&T> {
+        self.tree.matches(cidr).map(|counted| counted.hit()).collect()
+    }
+
+    // Like CidrTree::longest_match(), counting a hit for the CIDR matched
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<&T> {
+        self.tree.longest_match(cidr).map(|counted| counted.hit())
+    }
+
+    // Returns the data stored at exactly the given CIDR without counting a hit
+    pub fn get_exact(&self, cidr: &Cidr) -> Option<&T> {
+        self.tree.get_exact(cidr).map(|counted| &counted.data)
+    }
+
+    // Returns how many lookups have matched the given CIDR, if it is stored
+    pub fn hits(&self, cidr: &Cidr) -> Option<u64> {
+        self.tree.get_exact(cidr).map(|counted| counted.hits.load(Ordering::Relaxed))
+    }
+
+    // Returns every stored CIDR with its hit count, in prefix order
+    pub fn hit_counts(&self) -> Vec<(Cidr, u64)> {
+        self.tree.iter().map(|(cidr, counted)| (cidr, counted.hits.load(Ordering::Relaxed))).collect()
+    }
+
+    // Sets every hit count back to zero
+    pub fn reset_hits(&self) {
+        for (_, counted) in self.tree.iter() {
+            counted.hits.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn into_inner(self) -> CidrTree<T> {
+        self.tree.into_iter().map(|(cidr, counted)| (cidr, counted.data)).collect()
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_hit_counts() {
+    let mut t = CountingCidrTree::<&str>::new();
+    let wide = Cidr::from_str("10.0.0.0/8").unwrap();
+    let narrow = Cidr::from_str("10.1.0.0/16").unwrap();
+
+    t.insert(&wide, "a");
+    t.insert(&narrow, "b");
+
+    assert!(t.get(&Cidr::from_str("10.1.2.3").unwrap()) == vec![&"a", &"b"]);
+    assert!(t.longest_match(&Cidr::from_str("10.2.0.0").unwrap()) == Some(&"a"));
+    assert!(t.longest_match(&Cidr::from_str("11.0.0.0").unwrap()).is_none());
+    assert!(t.get_exact(&narrow) == Some(&"b"));
+
+    assert!(t.hits(&wide) == Some(2));
+    assert!(t.hits(&narrow) == Some(1));
+    assert!(t.hits(&Cidr::from_str("11.0.0.0/8").unwrap()).is_none());
+    assert!(t.hit_counts() == vec![(wide, 2), (narrow, 1)]);
+
+    t.reset_hits();
+    assert!(t.hit_counts() == vec![(wide, 0), (narrow, 0)]);
+
+    t.longest_match(&Cidr::from_str("10.1.0.0").unwrap());
+    t.insert(&narrow, "c");
+    assert!(t.hits(&narrow) == Some(0));
+    assert!(t.into_inner().get_exact(&narrow) == Some(&"c"));
+}
+
+#[test]
+fn test_hit_counts_on_miss() {
+    let mut t = CountingCidrTree::<&str>::new();
+    let wide = Cidr::from_str("10.0.0.0/8").unwrap();
+    let narrow = Cidr::from_str("10.1.0.0/16").unwrap();
+
+    // A miss on an empty tree counts nothing anywhere
+    assert!(t.get(&wide).is_empty() && t.longest_match(&wide).is_none());
+    assert!(t.hits(&wide).is_none() && t.hit_counts().is_empty());
+
+    t.insert(&wide, "a");
+    t.insert(&narrow, "b");
+
+    // Queries outside every stored CIDR, or wider than all of them, or in the
+    // other family, leave the counts at zero
+    assert!(t.get(&Cidr::from_str("11.0.0.0").unwrap()).is_empty());
+    assert!(t.longest_match(&Cidr::from_str("10.0.0.0/7").unwrap()).is_none());
+    assert!(t.longest_match(&Cidr::from_str("::ffff:10.1.0.1").unwrap()).is_none());
+    assert!(t.hit_counts() == vec![(wide, 0), (narrow, 0)]);
+
+    // A query only the wider CIDR covers counts no hit for the narrower one,
+    // and get_exact() never counts
+    assert!(t.longest_match(&Cidr::from_str("10.2.0.0").unwrap()) == Some(&"a"));
+    assert!(t.get_exact(&narrow) == Some(&"b") && t.get_exact(&wide) == Some(&"a"));
+    assert!(t.hit_counts() == vec![(wide, 1), (narrow, 0)]);
+
+    // A removed CIDR has no count at all
+    t.remove(&wide);
+    assert!(t.hits(&wide).is_none());
+    assert!(t.get(&Cidr::from_str("10.2.0.0").unwrap()).is_empty());
+    assert!(t.hit_counts() == vec![(narrow, 0)]);
+}
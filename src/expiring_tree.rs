@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+
+// A CidrTree whose entries can be given a time to live. Expired entries are
+// skipped by lookups straight away and removed for good by expire(), which
+// also prunes the branches they leave empty.
+pub struct ExpiringCidrTree<T> {
+    tree: CidrTree<Expiring<T>>,
+}
+
+#[derive(Debug)]
+struct Expiring<T> {
+    data: T,
+    // None for entries that never expire
+    deadline: Option<Instant>,
+}
+
+impl<T> Expiring<T> {
+    fn is_live(&self, now: Instant) -> bool {
+        self.deadline.is_none_or(|deadline| now < deadline)
+    }
+}
+
+impl<T> Default for ExpiringCidrTree<T> {
+    fn default() -> ExpiringCidrTree<T> {
+        ExpiringCidrTree::new()
+    }
+}
+
+impl<T> ExpiringCidrTree<T> {
+    pub fn new() -> ExpiringCidrTree<T> {
+        ExpiringCidrTree {
+            tree: CidrTree::new(),
+        }
+    }
+
+    // Stores data that never expires, returning the data it replaced, if any
+    pub fn insert(&mut self, cidr: &Cidr, data: T) -> Option<T> {
+        self.insert_entry(cidr, data, None)
+    }
+
+    // Stores data that expires once ttl has passed from now
+    pub fn insert_with_ttl(&mut self, cidr: &Cidr, data: T, ttl: Duration, now: Instant) -> Option<T> {
+        self.insert_entry(cidr, data, Some(now + ttl))
+    }
+
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        self.tree.remove(cidr).map(|old| old.data)
+    }
+
+    // Removes every entry that has expired by now, returning how many were
+    // removed
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.tree.len();
+        self.tree.retain(|_, entry| entry.is_live(now));
+        before - self.tree.len()
+    }
+
+    // Returns the number of entries stored, including any that have expired
+    // but haven't been removed by expire() yet
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // Like CidrTree::get(), ignoring entries expired by now
+    pub fn get(&self, cidr: &Cidr, now: Instant) -> Vec<&T> {
+        self.tree.matches(cidr).filter(|entry| entry.is_live(now)).map(|entry| &entry.data).collect()
+    }
+
+    // Like CidrTree::longest_match(), ignoring entries expired by now
+    pub fn longest_match(&self, cidr: &Cidr, now: Instant) -> Option<&T> {
+        self.tree.matches(cidr).filter(|entry| entry.is_live(now)).last().map(|entry| &entry.data)
+    }
+
+    // Returns the data stored at exactly the given CIDR, unless it has expired
+    pub fn get_exact(&self, cidr: &Cidr, now: Instant) -> Option<&T> {
+        self.tree.get_exact(cidr).filter(|entry| entry.is_live(now)).map(|entry| &entry.data)
+    }
+
+    fn insert_entry(&mut self, cidr: &Cidr, data: T, deadline: Option<Instant>) -> Option<T> {
+        let entry = Expiring {
+            data,
+            deadline,
+        };
+        self.tree.insert(cidr, entry).map(|old| old.data)
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_expire() {
+    let mut t = ExpiringCidrTree::<&str>::new();
+    let start = Instant::now();
+    let later = start + Duration::from_secs(60);
+
+    t.insert(&Cidr::from_str("10.0.0.0/8").unwrap(), "static");
+    t.insert_with_ttl(&Cidr::from_str("10.1.0.0/16").unwrap(), "feed", Duration::from_secs(30), start);
+    t.insert_with_ttl(&Cidr::from_str("10.2.0.0/16").unwrap(), "dhcp", Duration::from_secs(90), start);
+
+    let query = Cidr::from_str("10.1.2.3").unwrap();
+    assert!(t.get(&query, start) == vec![&"static", &"feed"]);
+    assert!(t.longest_match(&query, start) == Some(&"feed"));
+
+    // Expired entries are hidden from lookups before they're removed
+    assert!(t.get(&query, later) == vec![&"static"]);
+    assert!(t.longest_match(&query, later) == Some(&"static"));
+    assert!(t.get_exact(&Cidr::from_str("10.1.0.0/16").unwrap(), later).is_none());
+    assert!(t.len() == 3);
+
+    assert!(t.expire(later) == 1);
+    assert!(t.len() == 2);
+    assert!(t.get_exact(&Cidr::from_str("10.2.0.0/16").unwrap(), later) == Some(&"dhcp"));
+    assert!(t.expire(later) == 0);
+}
+
+#[test]
+fn test_expire_at_ttl_boundary() {
+    let mut t = ExpiringCidrTree::<&str>::new();
+    let cidr = Cidr::from_str("10.1.0.0/16").unwrap();
+    let ttl = Duration::from_secs(30);
+    let start = Instant::now();
+    let deadline = start + ttl;
+    let just_before = deadline - Duration::from_nanos(1);
+
+    // An entry is live up to, but not at, the end of its TTL
+    t.insert_with_ttl(&cidr, "feed", ttl, start);
+    assert!(t.get_exact(&cidr, just_before) == Some(&"feed"));
+    assert!(t.expire(just_before) == 0);
+    assert!(t.get_exact(&cidr, deadline).is_none());
+    assert!(t.get(&cidr, deadline).is_empty() && t.longest_match(&cidr, deadline).is_none());
+    assert!(t.expire(deadline) == 1 && t.is_empty());
+
+    // A zero TTL is expired at the moment it's inserted
+    t.insert_with_ttl(&cidr, "gone", Duration::from_secs(0), start);
+    assert!(t.get_exact(&cidr, start).is_none());
+    assert!(t.expire(start) == 1);
+
+    // Replacing an entry replaces its deadline too
+    t.insert_with_ttl(&cidr, "short", ttl, start);
+    assert!(t.insert(&cidr, "static") == Some("short"));
+    assert!(t.get_exact(&cidr, deadline) == Some(&"static"));
+    assert!(t.expire(deadline + ttl) == 0 && t.len() == 1);
+}
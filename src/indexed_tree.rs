@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Deref;
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+
+// A CidrTree that also indexes its entries by value, so that the CIDRs holding
+// a given value can be found without scanning the whole tree. Read-only
+// CidrTree methods are available through Deref; changes must go through the
+// methods here so the index stays in step.
+pub struct IndexedCidrTree<T> where T: Eq + Hash + Clone {
+    tree: CidrTree<T>,
+    index: HashMap<T, Vec<Cidr>>,
+}
+
+impl<T> Default for IndexedCidrTree<T> where T: Eq + Hash + Clone {
+    fn default() -> IndexedCidrTree<T> {
+        IndexedCidrTree::new()
+    }
+}
+
+impl<T> Deref for IndexedCidrTree<T> where T: Eq + Hash + Clone {
+    type Target = CidrTree<T>;
+
+    fn deref(&self) -> &CidrTree<T> {
+        &self.tree
+    }
+}
+
+impl<T> IndexedCidrTree<T> where T: Eq + Hash + Clone {
+    pub fn new() -> IndexedCidrTree<T> {
+        IndexedCidrTree {
+            tree: CidrTree::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    // Stores data at the given CIDR and returns the data it replaced, if any
+    pub fn insert(&mut self, cidr: &Cidr, data: T) -> Option<T> {
+        let key = cidr.canonicalize();
+        self.index.entry(data.clone()).or_default().push(key);
+        let replaced = self.tree.insert(cidr, data);
+        if let Some(ref old) = replaced {
+            self.unindex(old, &key);
+        }
+        replaced
+    }
+
+    // Removes and returns the data stored at exactly the given CIDR
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        let removed = self.tree.remove(cidr);
+        if let Some(ref old) = removed {
+            self.unindex(old, &cidr.canonicalize());
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.tree.clear();
+        self.index.clear();
+    }
+
+    // Returns the CIDRs whose data equals the given value, in no particular
+    // order
+    pub fn prefixes_for_value(&self, value: &T) -> &[Cidr] {
+        self.index.get(value).map_or(&[], |cidrs| cidrs.as_slice())
+    }
+
+    pub fn into_inner(self) -> CidrTree<T> {
+        self.tree
+    }
+
+    fn unindex(&mut self, value: &T, cidr: &Cidr) {
+        let now_empty = match self.index.get_mut(value) {
+            Some(cidrs) => {
+                // The same CIDR may have been indexed twice if a value replaced
+                // an equal one; only drop a single occurrence
+                if let Some(i) = cidrs.iter().position(|c| c == cidr) {
+                    cidrs.swap_remove(i);
+                }
+                cidrs.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            self.index.remove(value);
+        }
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_prefixes_for_value() {
+    let mut t = IndexedCidrTree::<u32>::new();
+
+    t.insert(&Cidr::from_str("10.0.0.0/8").unwrap(), 65001);
+    t.insert(&Cidr::from_str("192.0.2.0/24").unwrap(), 65001);
+    t.insert(&Cidr::from_str("2001:db8::/32").unwrap(), 65002);
+
+    let mut found = t.prefixes_for_value(&65001).to_vec();
+    found.sort_by_key(|cidr| cidr.length);
+    assert!(found == vec![Cidr::from_str("10.0.0.0/8").unwrap(), Cidr::from_str("192.0.2.0/24").unwrap()]);
+    assert!(t.prefixes_for_value(&65002) == [Cidr::from_str("2001:db8::/32").unwrap()]);
+    assert!(t.prefixes_for_value(&65003).is_empty());
+
+    // Replacing and removing keep the index in step
+    t.insert(&Cidr::from_str("10.0.0.0/8").unwrap(), 65002);
+    assert!(t.prefixes_for_value(&65001) == [Cidr::from_str("192.0.2.0/24").unwrap()]);
+    assert!(t.prefixes_for_value(&65002).len() == 2);
+
+    t.insert(&Cidr::from_str("10.0.0.0/8").unwrap(), 65002);
+    assert!(t.prefixes_for_value(&65002).len() == 2);
+
+    t.remove(&Cidr::from_str("192.0.2.0/24").unwrap());
+    assert!(t.prefixes_for_value(&65001).is_empty());
+
+    // Lookups go straight to the underlying tree
+    assert!(t.longest_match(&Cidr::from_str("10.1.2.3").unwrap()) == Some(&65002));
+    assert!(t.len() == 2);
+}
+
+#[test]
+fn test_prefixes_for_value_replace_and_remove() {
+    let mut t = IndexedCidrTree::<u32>::new();
+    let a = Cidr::from_str("10.0.0.0/8").unwrap();
+    let b = Cidr::from_str("10.1.0.0/16").unwrap();
+
+    // Replacing a value with an equal one leaves a single index entry, which
+    // removing then clears
+    t.insert(&a, 1);
+    assert!(t.insert(&a, 1) == Some(1));
+    assert!(t.prefixes_for_value(&1) == [a]);
+    assert!(t.remove(&a) == Some(1));
+    assert!(t.prefixes_for_value(&1).is_empty() && t.is_empty());
+
+    // A non-canonical key is indexed by its canonical form, so removing by
+    // either finds it
+    t.insert(&Cidr::from_str("10.1.2.3/16").unwrap(), 2);
+    assert!(t.prefixes_for_value(&2) == [b]);
+    assert!(t.remove(&b) == Some(2));
+    assert!(t.prefixes_for_value(&2).is_empty());
+
+    // Moving a CIDR back and forth between values, and removing a CIDR that
+    // isn't stored, keep every value's list exact
+    t.insert(&a, 1);
+    t.insert(&b, 1);
+    t.insert(&a, 2);
+    t.insert(&a, 1);
+    assert!(t.prefixes_for_value(&2).is_empty());
+    assert!(t.prefixes_for_value(&1).len() == 2);
+    assert!(t.remove(&Cidr::from_str("10.2.0.0/16").unwrap()).is_none());
+    assert!(t.prefixes_for_value(&1).len() == 2);
+    assert!(t.remove(&a) == Some(1));
+    assert!(t.prefixes_for_value(&1) == [b]);
+
+    t.clear();
+    assert!(t.prefixes_for_value(&1).is_empty() && t.is_empty());
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+use std::fmt::Debug;
+use std::net::IpAddr;
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+use prefix::Prefix;
+
+// Widest branch a node may take, i.e. at most 2^16 children
+const MAX_BRANCH: u8 = 16;
+
+// An immutable level-compressed trie (LC-trie, after Nilsson and Karlsson) for
+// fast longest-match lookups, e.g. over a full BGP table. Where the stored
+// CIDRs are dense, a node branches on several bits at once, so a lookup takes
+// a handful of array accesses instead of one pointer chase per bit. Created
+// with CidrTree::to_lc_trie().
+//
+// The trie's leaves point into the sorted entries; a lookup walks to a leaf,
+// then follows each entry's link to the longest stored CIDR containing it
+// until it finds one covering the query.
+pub struct LcTrie<T> {
+    entries: Vec<(Cidr, T)>,
+    // Index of the longest other entry containing each entry
+    parents: Vec<Option<usize>>,
+    v4: Vec<Node>,
+    v6: Vec<Node>,
+}
+
+// A node branching on the `branch` bits starting at bit `pos`, whose children
+// are the 2^branch nodes starting at `adr`; or, with a branch of 0, a leaf
+// pointing at entry `adr`
+#[derive(Clone, Copy)]
+struct Node {
+    branch: u8,
+    pos: u8,
+    adr: usize,
+}
+
+impl<T> Debug for LcTrie<T> where T: Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<T> CidrTree<T> where T: Clone {
+    // Builds an LcTrie holding a copy of the entries
+    pub fn to_lc_trie(&self) -> LcTrie<T> {
+        LcTrie::build(self.iter().map(|(cidr, data)| (cidr, data.clone())).collect())
+    }
+}
+
+impl<T> LcTrie<T> {
+    // The entries must be canonical, distinct and in the order of
+    // CidrTree::iter()
+    fn build(entries: Vec<(Cidr, T)>) -> LcTrie<T> {
+        let mut parents = Vec::with_capacity(entries.len());
+        let mut open: Vec<usize> = Vec::new();
+        for (i, (cidr, _)) in entries.iter().enumerate() {
+            while open.last().is_some_and(|&j| !entries[j].0.contains(cidr)) {
+                open.pop();
+            }
+            parents.push(open.last().cloned());
+            open.push(i);
+        }
+        let v4_len = entries.iter().take_while(|(cidr, _)| cidr.is_ipv4()).count();
+        let mut trie = LcTrie {
+            entries,
+            parents,
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        trie.v4 = trie.build_nodes(0, v4_len);
+        trie.v6 = trie.build_nodes(v4_len, trie.entries.len());
+        trie
+    }
+
+    fn build_nodes(&self, lo: usize, hi: usize) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        if lo < hi {
+            nodes.push(Node { branch: 0, pos: 0, adr: lo });
+            self.build_node(&mut nodes, 0, lo, hi);
+        }
+        nodes
+    }
+
+    // Fills in nodes[index] for the entries in lo..hi, which all lie within
+    // the CIDR the node covers
+    fn build_node(&self, nodes: &mut Vec<Node>, index: usize, mut lo: usize, hi: usize) {
+        // Entries containing all the others are reached through the parent
+        // links of whichever leaf a lookup ends on
+        while hi - lo > 1 && self.entries[lo].0.contains(&self.entries[hi - 1].0) {
+            lo += 1;
+        }
+        if hi - lo == 1 {
+            nodes[index] = Node { branch: 0, pos: 0, adr: lo };
+            return;
+        }
+
+        // Branch on as many bits past the common prefix as keep at least half
+        // the children occupied. No entry may end within the branch bits, or a
+        // lookup could miss it by landing in a child it doesn't cover.
+        let entries = &self.entries[lo..hi];
+        let pos = entries[0].0.common_prefix_len(&entries[entries.len() - 1].0);
+        let min_length = entries.iter().map(|(cidr, _)| cidr.length).min().unwrap();
+        let mut branch = 1;
+        while branch < MAX_BRANCH && pos + branch < min_length {
+            let occupied = count_distinct(entries.iter().map(|(cidr, _)| extract(cidr, pos, branch + 1)));
+            if occupied * 2 < 1 << (branch + 1) {
+                break;
+            }
+            branch += 1;
+        }
+
+        let adr = nodes.len();
+        nodes[index] = Node { branch, pos, adr };
+        // Empty children point at any entry here; its parent links still lead
+        // to every entry covering the node
+        nodes.extend((0..1usize << branch).map(|_| Node { branch: 0, pos: 0, adr: lo }));
+        let mut start = lo;
+        while start < hi {
+            let slot = extract(&self.entries[start].0, pos, branch);
+            let end = start + self.entries[start..hi].iter()
+                .take_while(|(cidr, _)| extract(cidr, pos, branch) == slot)
+                .count();
+            self.build_node(nodes, adr + slot, start, end);
+            start = end;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Returns the data of the most specific stored CIDR covering the query
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<&T> {
+        self.longest_match_with_prefix(cidr).map(|(_, data)| data)
+    }
+
+    // Like longest_match(), but also returns the stored CIDR the data belongs to
+    pub fn longest_match_with_prefix(&self, cidr: &Cidr) -> Option<(Cidr, &T)> {
+        let index = self.find(&cidr.canonicalize())?;
+        let (cidr, ref data) = self.entries[index];
+        Some((cidr, data))
+    }
+
+    // Like longest_match(), for a single address
+    pub fn longest_match_ip(&self, ip: IpAddr) -> Option<&T> {
+        self.longest_match(&Cidr::host(ip))
+    }
+
+    // Returns a vector of all the data that applies to the queried CIDR, from
+    // least to most specific
+    pub fn get(&self, cidr: &Cidr) -> Vec<&T> {
+        let mut results = Vec::new();
+        let mut index = self.find(&cidr.canonicalize());
+        while let Some(i) = index {
+            results.push(&self.entries[i].1);
+            index = self.parents[i];
+        }
+        results.reverse();
+        results
+    }
+
+    // Iterates over every stored CIDR and its data in the same order as
+    // CidrTree::iter()
+    pub fn iter(&self) -> impl Iterator<Item = (Cidr, &T)> {
+        self.entries.iter().map(|(cidr, data)| (*cidr, data))
+    }
+
+    // Returns the index of the longest entry containing the canonical query
+    fn find(&self, cidr: &Cidr) -> Option<usize> {
+        let nodes = match cidr.prefix {
+            Prefix::V4(_) => &self.v4,
+            Prefix::V6(_) => &self.v6,
+        };
+        let mut node = *nodes.first()?;
+        while node.branch > 0 {
+            node = nodes[node.adr + extract(cidr, node.pos, node.branch)];
+        }
+        let mut index = Some(node.adr);
+        while let Some(i) = index {
+            if self.entries[i].0.contains(cidr) {
+                return Some(i);
+            }
+            index = self.parents[i];
+        }
+        None
+    }
+}
+
+// Returns the `count` bits of the CIDR's address starting at bit `pos`,
+// counting from the most significant bit
+fn extract(cidr: &Cidr, pos: u8, count: u8) -> usize {
+    let aligned = cidr.prefix.to_int() << (128 - cidr.prefix.bits() as u32);
+    ((aligned << pos) >> (128 - count as u32)) as usize
+}
+
+// Counts the distinct values of a sorted sequence
+fn count_distinct<I: Iterator<Item = usize>>(values: I) -> usize {
+    let mut count = 0;
+    let mut last = None;
+    for value in values {
+        if last != Some(value) {
+            count += 1;
+            last = Some(value);
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_lc_trie() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    let mut t = CidrTree::<u32>::new();
+    t.insert(c("10.0.0.0/8"), 1);
+    t.insert(c("10.1.0.0/16"), 2);
+    t.insert(c("10.1.2.0/24"), 3);
+    t.insert(c("10.2.0.0/16"), 4);
+    t.insert(c("192.168.0.0/16"), 5);
+    t.insert(c("2001:db8::/32"), 6);
+    t.insert(c("::/0"), 7);
+
+    let lc = t.to_lc_trie();
+    assert!(lc.len() == 7);
+    assert!(lc.longest_match(&c("10.1.2.3")) == Some(&3));
+    assert!(lc.longest_match(&c("10.1.3.0/24")) == Some(&2));
+    assert!(lc.longest_match(&c("10.3.0.0")) == Some(&1));
+    assert!(lc.longest_match(&c("10.0.0.0/7")).is_none());
+    assert!(lc.longest_match(&c("11.0.0.0")).is_none());
+    assert!(lc.longest_match_with_prefix(&c("192.168.1.1")) == Some((c("192.168.0.0/16"), &5)));
+    assert!(lc.longest_match_ip("2001:db8::1".parse().unwrap()) == Some(&6));
+    assert!(lc.longest_match(&c("2001:db9::1")) == Some(&7));
+    assert!(lc.get(&c("10.1.2.3")) == vec![&1, &2, &3]);
+    assert!(lc.get(&c("2001:db8::/48")) == vec![&7, &6]);
+    assert!(lc.iter().collect::<Vec<_>>() == t.iter().collect::<Vec<_>>());
+
+    // A dense block is resolved by one node branching on all eight bits
+    let dense = (0..256).map(|i| (Cidr::from_u32(0x0a000000 | i << 8, 24).unwrap(), i)).collect::<CidrTree<_>>();
+    let lc = dense.to_lc_trie();
+    assert!(lc.v4.len() == 1 + 256 && lc.v4[0].branch == 8);
+    assert!(lc.longest_match(&c("10.0.7.1")) == Some(&7));
+
+    let empty = CidrTree::<u32>::new().to_lc_trie();
+    assert!(empty.is_empty());
+    assert!(empty.longest_match(&c("10.0.0.1")).is_none());
+}
+
+#[test]
+fn test_lc_trie_matches_cidr_tree() {
+    // Deterministic pseudo-random entries and queries, checked against CidrTree
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut random_cidr = |max_length: u8| {
+        let n = next();
+        match n % 3 {
+            0 => Cidr::from_u128(((n as u128) << 64) & (0xffff_f000 << 96), (n >> 8) as u8 % (max_length + 1)).unwrap(),
+            _ => Cidr::from_u32((n >> 32) as u32 & 0xfff0_00ff, (n >> 8) as u8 % (max_length.min(32) + 1)).unwrap(),
+        }.canonicalize()
+    };
+    let mut t = CidrTree::new();
+    for i in 0..3000 {
+        t.insert(random_cidr(40), i);
+    }
+    let lc = t.to_lc_trie();
+    for _ in 0..3000 {
+        let query = random_cidr(128);
+        assert!(lc.longest_match(&query) == t.longest_match(&query));
+        assert!(lc.get(&query) == t.get(query));
+    }
+}
@@ -1,3 +1,19 @@
-mod prefix;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "rand")]
+extern crate rand;
+
+pub mod prefix;
 pub mod cidr;
 pub mod cidr_tree;
+pub mod indexed_tree;
+pub mod multi_tree;
+pub mod counting_tree;
+pub mod expiring_tree;
+pub mod bounded_tree;
+pub mod persistent_tree;
+pub mod patricia_tree;
+pub mod lc_trie;
+pub mod special;
+#[cfg(feature = "rayon")]
+mod parallel;
@@ -0,0 +1,134 @@
+use std::ops::Deref;
+use cidr::Cidr;
+use cidr_tree::{CidrTree, Entry};
+
+// A CidrTree that keeps every value inserted for a CIDR instead of replacing
+// the previous one. Read-only CidrTree methods are available through Deref,
+// with each CIDR's values as a Vec.
+pub struct CidrMultiTree<T> {
+    tree: CidrTree<Vec<T>>,
+}
+
+impl<T> Default for CidrMultiTree<T> {
+    fn default() -> CidrMultiTree<T> {
+        CidrMultiTree::new()
+    }
+}
+
+impl<T> Deref for CidrMultiTree<T> {
+    type Target = CidrTree<Vec<T>>;
+
+    fn deref(&self) -> &CidrTree<Vec<T>> {
+        &self.tree
+    }
+}
+
+impl<T> CidrMultiTree<T> {
+    pub fn new() -> CidrMultiTree<T> {
+        CidrMultiTree {
+            tree: CidrTree::new(),
+        }
+    }
+
+    // Adds data to the values stored at the given CIDR
+    pub fn insert(&mut self, cidr: &Cidr, data: T) {
+        self.tree.entry(cidr).or_default().push(data);
+    }
+
+    // Removes and returns every value stored at exactly the given CIDR
+    pub fn remove(&mut self, cidr: &Cidr) -> Vec<T> {
+        self.tree.remove(cidr).unwrap_or_default()
+    }
+
+    // Removes the values stored at exactly the given CIDR that f rejects,
+    // dropping the CIDR altogether if none are left
+    pub fn retain_values<F>(&mut self, cidr: &Cidr, f: F) where F: FnMut(&T) -> bool {
+        let now_empty = match self.tree.entry(cidr) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().retain(f);
+                entry.get().is_empty()
+            },
+            Entry::Vacant(_) => false,
+        };
+        if now_empty {
+            self.tree.remove(cidr);
+        }
+    }
+
+    // Returns every value stored at a CIDR covering the query, from the least
+    // to the most specific CIDR, in insertion order within each CIDR
+    pub fn get(&self, cidr: &Cidr) -> Vec<&T> {
+        self.tree.matches(cidr).flat_map(|values| values.iter()).collect()
+    }
+
+    // Returns the values stored at exactly the given CIDR
+    pub fn get_exact(&self, cidr: &Cidr) -> &[T] {
+        self.tree.get_exact(cidr).map_or(&[], |values| values.as_slice())
+    }
+
+    // Returns the total number of values stored, across all CIDRs
+    pub fn value_count(&self) -> usize {
+        self.tree.iter().map(|(_, values)| values.len()).sum()
+    }
+
+    pub fn into_inner(self) -> CidrTree<Vec<T>> {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_multi_values() {
+    let mut t = CidrMultiTree::<&str>::new();
+
+    t.insert(&Cidr::from_str("10.0.0.0/8").unwrap(), "corp");
+    t.insert(&Cidr::from_str("10.1.0.0/16").unwrap(), "lab");
+    t.insert(&Cidr::from_str("10.1.0.0/16").unwrap(), "owner:alice");
+    t.insert(&Cidr::from_str("10.1.0.0/16").unwrap(), "owner:bob");
+
+    assert!(t.get(&Cidr::from_str("10.1.2.3").unwrap()) == vec![&"corp", &"lab", &"owner:alice", &"owner:bob"]);
+    assert!(t.get_exact(&Cidr::from_str("10.1.0.0/16").unwrap()) == ["lab", "owner:alice", "owner:bob"]);
+    assert!(t.len() == 2);
+    assert!(t.value_count() == 4);
+
+    t.retain_values(&Cidr::from_str("10.1.0.0/16").unwrap(), |v| !v.starts_with("owner:"));
+    assert!(t.get_exact(&Cidr::from_str("10.1.0.0/16").unwrap()) == ["lab"]);
+
+    t.retain_values(&Cidr::from_str("10.1.0.0/16").unwrap(), |_| false);
+    assert!(t.len() == 1);
+
+    assert!(t.remove(&Cidr::from_str("10.0.0.0/8").unwrap()) == vec!["corp"]);
+    assert!(t.remove(&Cidr::from_str("10.0.0.0/8").unwrap()).is_empty());
+    assert!(t.is_empty());
+}
+
+#[test]
+fn test_multi_values_edges() {
+    let mut t = CidrMultiTree::<u32>::new();
+    let block = Cidr::from_str("10.1.0.0/16").unwrap();
+
+    // Nothing stored: lookups are empty and retaining is a no-op
+    assert!(t.get(&block).is_empty() && t.get_exact(&block).is_empty());
+    t.retain_values(&block, |_| false);
+    assert!(t.is_empty() && t.value_count() == 0);
+
+    // Equal values are kept side by side, and non-canonical keys land on the
+    // same CIDR
+    t.insert(&block, 1);
+    t.insert(&Cidr::from_str("10.1.2.3/16").unwrap(), 1);
+    assert!(t.get_exact(&block) == [1, 1] && t.len() == 1);
+
+    // Keeping everything leaves the CIDR and its order alone
+    t.insert(&block, 2);
+    t.retain_values(&block, |_| true);
+    assert!(t.get_exact(&block) == [1, 1, 2]);
+    t.retain_values(&block, |&v| v == 2);
+    assert!(t.get_exact(&block) == [2] && t.value_count() == 1);
+
+    // The wider CIDR has nothing to retain, and the query misses other families
+    t.retain_values(&Cidr::from_str("10.0.0.0/8").unwrap(), |_| false);
+    assert!(t.len() == 1);
+    assert!(t.get(&Cidr::from_str("::ffff:10.1.0.1").unwrap()).is_empty());
+}
@@ -0,0 +1,50 @@
+use std::net::IpAddr;
+use rayon::prelude::*;
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+
+// Number of queries each rayon task handles. Large enough that each task
+// benefits from lookup_many()'s path sharing.
+const CHUNK_SIZE: usize = 4096;
+
+impl<T> CidrTree<T> where T: Sync {
+    // Like lookup_many(), splitting the queries across rayon's thread pool
+    pub fn par_lookup_many(&self, cidrs: &[Cidr]) -> Vec<Option<&T>> {
+        cidrs.par_chunks(CHUNK_SIZE)
+            .flat_map_iter(|chunk| self.lookup_many(chunk))
+            .collect()
+    }
+
+    // Returns the data of the most specific stored CIDR covering each address,
+    // in the same order, splitting the addresses across rayon's thread pool
+    pub fn par_classify(&self, ips: &[IpAddr]) -> Vec<Option<&T>> {
+        ips.par_chunks(CHUNK_SIZE)
+            .flat_map_iter(|chunk| {
+                let hosts = chunk.iter().map(|&ip| Cidr::host(ip)).collect::<Vec<_>>();
+                self.lookup_many(&hosts)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_par_classify() {
+    let mut t = CidrTree::<u32>::new();
+    t.insert(Cidr::from_str("10.0.0.0/8").unwrap(), 1);
+    t.insert(Cidr::from_str("10.1.0.0/16").unwrap(), 2);
+    t.insert(Cidr::from_str("2001:db8::/32").unwrap(), 3);
+
+    let ips = (0..10000u32)
+        .map(|i| IpAddr::from([10, (i % 3) as u8, (i >> 8) as u8, i as u8]))
+        .chain(vec!["2001:db8::1".parse().unwrap(), "::1".parse().unwrap()])
+        .collect::<Vec<IpAddr>>();
+    let expected = ips.iter().map(|&ip| t.longest_match(&Cidr::host(ip))).collect::<Vec<_>>();
+    assert!(t.par_classify(&ips) == expected);
+
+    let hosts = ips.iter().map(|&ip| Cidr::host(ip)).collect::<Vec<_>>();
+    assert!(t.par_lookup_many(&hosts) == expected);
+    assert!(expected[..3] == [Some(&1), Some(&2), Some(&1)]);
+}
@@ -0,0 +1,424 @@
+use std::fmt;
+use std::fmt::Debug;
+use std::iter::FromIterator;
+use std::mem;
+use std::net::IpAddr;
+use std::str::FromStr;
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+use prefix::Prefix;
+
+// A path-compressed (Patricia) alternative to CidrTree. CidrTree allocates a
+// node for every bit of every stored CIDR, up to 128 per IPv6 entry; here a
+// node exists only where an entry is stored or two branches diverge, and each
+// node records the full CIDR it represents, so the bits between a node and its
+// parent are skipped in a single comparison. Sparse tables, such as routing
+// tables, need far fewer nodes and pointer chases.
+//
+// It is not a drop-in replacement for CidrTree. It covers only the core of
+// CidrTree's API: inserting, removing, exact and covering lookups, and
+// iteration, with the same signatures and results. There is no entry API,
+// cursor, subtree view, walk(), range(), gaps(), merge() or diff(); code that
+// needs those should use a CidrTree, or convert with to_tree().
+#[derive(Clone)]
+pub struct PatriciaTree<T> {
+    v4: Node<T>,
+    v6: Node<T>,
+    len: usize,
+}
+
+#[derive(Clone)]
+struct Node<T> {
+    key: Cidr,
+    children: [Option<Box<Node<T>>>; 2],
+    data: Option<T>,
+}
+
+impl<T> Debug for PatriciaTree<T> where T: Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<T> Default for PatriciaTree<T> {
+    fn default() -> PatriciaTree<T> {
+        PatriciaTree::new()
+    }
+}
+
+impl<T> FromIterator<(Cidr, T)> for PatriciaTree<T> {
+    fn from_iter<I: IntoIterator<Item = (Cidr, T)>>(iter: I) -> PatriciaTree<T> {
+        let mut tree = PatriciaTree::new();
+        for (cidr, data) in iter {
+            tree.insert(cidr, data);
+        }
+        tree
+    }
+}
+
+impl<T> Extend<(Cidr, T)> for PatriciaTree<T> {
+    fn extend<I: IntoIterator<Item = (Cidr, T)>>(&mut self, iter: I) {
+        for (cidr, data) in iter {
+            self.insert(cidr, data);
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a PatriciaTree<T> {
+    type Item = (Cidr, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> From<CidrTree<T>> for PatriciaTree<T> {
+    fn from(tree: CidrTree<T>) -> PatriciaTree<T> {
+        tree.into_iter().collect()
+    }
+}
+
+impl<T> PatriciaTree<T> {
+    pub fn new() -> PatriciaTree<T> {
+        PatriciaTree {
+            v4: Node::new(Cidr::new(Prefix::V4([0; 4]), 0), None),
+            v6: Node::new(Cidr::new(Prefix::V6([0; 16]), 0), None),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Returns the number of nodes allocated, including the two roots; useful for
+    // comparing memory use against CidrTree::stats()
+    pub fn node_count(&self) -> usize {
+        self.v4.count() + self.v6.count()
+    }
+
+    // Returns a vector of all the data that applies to the queried CIDR, from
+    // least to most specific
+    pub fn get<C: Into<Cidr>>(&self, cidr: C) -> Vec<&T> {
+        self.matches(cidr.into()).map(|(_, data)| data).collect()
+    }
+
+    // Like get(), but also returns the stored CIDR each piece of data belongs to
+    pub fn get_with_prefix<C: Into<Cidr>>(&self, cidr: C) -> Vec<(Cidr, &T)> {
+        self.matches(cidr.into()).collect()
+    }
+
+    pub fn get_from_str(&self, cidr: &str) -> Vec<&T> {
+        self.get(Cidr::from_str(cidr).unwrap())
+    }
+
+    // Returns the data of the most specific stored CIDR covering the query
+    pub fn longest_match<C: Into<Cidr>>(&self, cidr: C) -> Option<&T> {
+        self.longest_match_with_prefix(cidr).map(|(_, data)| data)
+    }
+
+    // Like longest_match(), but also returns the stored CIDR that matched
+    pub fn longest_match_with_prefix<C: Into<Cidr>>(&self, cidr: C) -> Option<(Cidr, &T)> {
+        self.matches(cidr.into()).last()
+    }
+
+    // Returns the data stored at exactly the given CIDR
+    pub fn get_exact<C: Into<Cidr>>(&self, cidr: C) -> Option<&T> {
+        let cidr = cidr.into().canonicalize();
+        let mut node = self.root(&cidr);
+        while node.key != cidr {
+            node = node.child_towards(&cidr).filter(|child| child.key.contains(&cidr))?;
+        }
+        node.data.as_ref()
+    }
+
+    // Returns true if data is stored at exactly the given CIDR
+    pub fn has_exact<C: Into<Cidr>>(&self, cidr: C) -> bool {
+        self.get_exact(cidr).is_some()
+    }
+
+    // Returns true if any stored CIDR covers the given one
+    pub fn covers<C: Into<Cidr>>(&self, cidr: C) -> bool {
+        self.matches(cidr.into()).next().is_some()
+    }
+
+    // Returns true if any stored CIDR covers the given address
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.covers(ip)
+    }
+
+    // Stores data at the given CIDR and returns the data it replaced, if any
+    pub fn insert<C: Into<Cidr>>(&mut self, cidr: C, data: T) -> Option<T> {
+        let cidr = cidr.into().canonicalize();
+        let replaced = self.root_mut(&cidr).insert(cidr, data);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    // Removes and returns the data stored at exactly the given CIDR, collapsing
+    // nodes that no longer hold data or join two branches
+    pub fn remove<C: Into<Cidr>>(&mut self, cidr: C) -> Option<T> {
+        let cidr = cidr.into().canonicalize();
+        let removed = self.root_mut(&cidr).remove(&cidr);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        *self = PatriciaTree::new();
+    }
+
+    // Iterates over every stored CIDR and its data in the same order as
+    // CidrTree::iter()
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![&self.v6, &self.v4],
+        }
+    }
+
+    // Copies the entries into a regular CidrTree
+    pub fn to_tree(&self) -> CidrTree<T> where T: Clone {
+        self.iter().map(|(cidr, data)| (cidr, data.clone())).collect()
+    }
+
+    // The stored CIDRs covering the query, from least to most specific,
+    // found by walking down towards it without allocating
+    fn matches(&self, cidr: Cidr) -> Matches<'_, T> {
+        let cidr = cidr.canonicalize();
+        Matches {
+            node: Some(self.root(&cidr)),
+            cidr,
+        }
+    }
+
+    fn root(&self, cidr: &Cidr) -> &Node<T> {
+        match cidr.prefix {
+            Prefix::V4(_) => &self.v4,
+            Prefix::V6(_) => &self.v6,
+        }
+    }
+
+    fn root_mut(&mut self, cidr: &Cidr) -> &mut Node<T> {
+        match cidr.prefix {
+            Prefix::V4(_) => &mut self.v4,
+            Prefix::V6(_) => &mut self.v6,
+        }
+    }
+}
+
+// Iterator over the entries of a PatriciaTree, created by PatriciaTree::iter()
+pub struct Iter<'a, T> where T: 'a {
+    stack: Vec<&'a Node<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Cidr, &'a T);
+
+    fn next(&mut self) -> Option<(Cidr, &'a T)> {
+        while let Some(node) = self.stack.pop() {
+            self.stack.extend(node.children.iter().rev().flatten().map(|child| &**child));
+            if let Some(ref d) = node.data {
+                return Some((node.key, d));
+            }
+        }
+        None
+    }
+}
+
+struct Matches<'a, T> where T: 'a {
+    node: Option<&'a Node<T>>,
+    cidr: Cidr,
+}
+
+impl<'a, T> Iterator for Matches<'a, T> {
+    type Item = (Cidr, &'a T);
+
+    fn next(&mut self) -> Option<(Cidr, &'a T)> {
+        while let Some(node) = self.node {
+            let cidr = self.cidr;
+            self.node = node.child_towards(&cidr).filter(|child| child.key.contains(&cidr));
+            if let Some(ref d) = node.data {
+                return Some((node.key, d));
+            }
+        }
+        None
+    }
+}
+
+impl<T> Node<T> {
+    fn new(key: Cidr, data: Option<T>) -> Node<T> {
+        Node {
+            key,
+            children: [None, None],
+            data,
+        }
+    }
+
+    // The child on the side of the given CIDR, which must be longer than this
+    // node's key and within it
+    fn child_towards(&self, cidr: &Cidr) -> Option<&Node<T>> {
+        if cidr.length == self.key.length {
+            return None;
+        }
+        self.children[self.branch(cidr)].as_deref()
+    }
+
+    fn branch(&self, cidr: &Cidr) -> usize {
+        cidr.prefix.bit(self.key.length as usize) as usize
+    }
+
+    // Inserts below this node, whose key must contain the CIDR
+    fn insert(&mut self, cidr: Cidr, data: T) -> Option<T> {
+        if cidr == self.key {
+            return self.data.replace(data);
+        }
+        let slot = &mut self.children[self.branch(&cidr)];
+        let child = match *slot {
+            Some(ref mut child) => child,
+            None => {
+                *slot = Some(Box::new(Node::new(cidr, Some(data))));
+                return None;
+            }
+        };
+        if child.key.contains(&cidr) {
+            return child.insert(cidr, data);
+        }
+        // The new CIDR sits between this node and the child, either directly
+        // above the child or where the two diverge
+        let key = cidr.common_supernet(&child.key);
+        let mut parent = Box::new(Node::new(key, None));
+        if key == cidr {
+            parent.data = Some(data);
+        } else {
+            parent.children[parent.branch(&cidr)] = Some(Box::new(Node::new(cidr, Some(data))));
+        }
+        let old_child = mem::replace(child, parent);
+        let branch = child.branch(&old_child.key);
+        child.children[branch] = Some(old_child);
+        None
+    }
+
+    fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        if *cidr == self.key {
+            return self.data.take();
+        }
+        let slot = &mut self.children[self.branch(cidr)];
+        let removed = match *slot {
+            Some(ref mut child) if child.key.contains(cidr) => child.remove(cidr),
+            _ => return None,
+        };
+        // A node without data is only worth keeping where two branches meet
+        let collapse = match *slot {
+            Some(ref child) if child.data.is_none() => {
+                let [ref zero, ref one] = child.children;
+                zero.is_none() || one.is_none()
+            },
+            _ => false,
+        };
+        if collapse {
+            let child = slot.take().unwrap();
+            let [zero, one] = child.children;
+            *slot = zero.or(one);
+        }
+        removed
+    }
+
+    fn count(&self) -> usize {
+        1 + self.children.iter().flatten().map(|child| child.count()).sum::<usize>()
+    }
+}
+
+
+#[test]
+fn test_patricia_tree() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    let mut t = PatriciaTree::<u32>::new();
+    assert!(t.insert(c("10.0.0.0/8"), 1).is_none());
+    assert!(t.insert(c("10.1.0.0/16"), 2).is_none());
+    assert!(t.insert(c("10.1.2.0/24"), 3).is_none());
+    assert!(t.insert(c("10.2.0.0/16"), 4).is_none());
+    assert!(t.insert(c("10.1.0.0/16"), 5) == Some(2));
+    assert!(t.insert(c("2001:db8::/32"), 6).is_none());
+    assert!(t.len() == 5);
+
+    assert!(t.get(c("10.1.2.3")) == vec![&1, &5, &3]);
+    assert!(t.get(c("10.3.0.0/16")) == vec![&1]);
+    assert!(t.get(c("11.0.0.0/8")).is_empty());
+    assert!(t.longest_match(c("2001:db8::1")) == Some(&6));
+    assert!(t.longest_match_with_prefix(c("10.1.2.3")) == Some((c("10.1.2.0/24"), &3)));
+    assert!(t.get_with_prefix(c("10.2.0.1")) == vec![(c("10.0.0.0/8"), &1), (c("10.2.0.0/16"), &4)]);
+    assert!(t.get_from_str("10.1.9.9") == vec![&1, &5]);
+    assert!(t.contains_ip("10.9.9.9".parse().unwrap()) && !t.contains_ip("::1".parse().unwrap()));
+    assert!(t.get_exact(c("10.1.0.0/16")) == Some(&5));
+    assert!(t.get_exact(c("10.0.0.0/15")).is_none());
+    assert!(t.has_exact(c("10.2.0.0/16")));
+    assert!(t.covers(c("10.200.0.1")));
+    assert!(!t.covers(c("2001:db9::")));
+
+    // 10.1.0.0/16 and 10.2.0.0/16 diverge at 10.0.0.0/14, which needs a node
+    // of its own
+    assert!(t.node_count() == 2 + 5 + 1);
+
+    assert!(t.remove(c("10.1.0.0/16")) == Some(5));
+    assert!(t.remove(c("10.1.0.0/16")).is_none());
+    assert!(t.get(c("10.1.2.3")) == vec![&1, &3]);
+    assert!(t.remove(c("10.2.0.0/16")) == Some(4));
+    assert!(t.node_count() == 2 + 3);
+    assert!(t.iter().collect::<Vec<_>>() ==
+            vec![(c("10.0.0.0/8"), &1), (c("10.1.2.0/24"), &3), (c("2001:db8::/32"), &6)]);
+}
+
+#[test]
+fn test_patricia_tree_sparse() {
+    let mut t = PatriciaTree::new();
+    t.insert(Cidr::from_str("2001:db8::1").unwrap(), ());
+    assert!(t.node_count() == 3);
+    t.insert(Cidr::from_str("::/0").unwrap(), ());
+    assert!(t.node_count() == 3);
+    assert!(t.get(Cidr::from_str("2001:db8::1").unwrap()).len() == 2);
+
+    t.extend(vec![(Cidr::from_str("10.0.0.0/8").unwrap(), ())]);
+    assert!(t.len() == 3 && (&t).into_iter().count() == 3);
+    t.clear();
+    assert!(t.is_empty() && t.node_count() == 2);
+}
+
+#[test]
+fn test_patricia_tree_matches_cidr_tree() {
+    // Deterministic pseudo-random entries, checked against CidrTree
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut cidr_tree = CidrTree::new();
+    let mut patricia = PatriciaTree::new();
+    for i in 0..2000 {
+        let n = next();
+        let cidr = match n % 2 {
+            0 => Cidr::from_u32((n >> 32) as u32 & 0xff00ffff, (n >> 8) as u8 % 33).unwrap(),
+            _ => Cidr::from_u128(((n as u128) << 64) & (0xffff_ff00 << 96), (n >> 8) as u8 % 129).unwrap(),
+        };
+        if n % 5 == 0 {
+            assert!(cidr_tree.remove(&cidr) == patricia.remove(cidr));
+        } else {
+            assert!(cidr_tree.insert(cidr, i) == patricia.insert(cidr, i));
+        }
+        assert!(cidr_tree.get(cidr) == patricia.get(cidr));
+    }
+    assert!(cidr_tree.len() == patricia.len());
+    assert!(patricia.to_tree() == cidr_tree);
+    assert!(PatriciaTree::from(cidr_tree).iter().collect::<Vec<_>>() ==
+            patricia.iter().collect::<Vec<_>>());
+}
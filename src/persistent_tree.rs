@@ -0,0 +1,317 @@
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::Arc;
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+use prefix::Prefix;
+
+// An immutable-by-sharing CidrTree. Clones share all their nodes, and a
+// mutation copies only the nodes on the path to the changed CIDR, so taking a
+// point-in-time view of a large table for concurrent readers is cheap. Created
+// with CidrTree::snapshot() or new().
+pub struct PersistentCidrTree<T> {
+    v4: Arc<Node<T>>,
+    v6: Arc<Node<T>>,
+    len: usize,
+}
+
+#[derive(Clone)]
+struct Node<T> {
+    zero: Option<Arc<Node<T>>>,
+    one: Option<Arc<Node<T>>>,
+    data: Option<T>,
+}
+
+// Only reference counts are touched; no nodes or data are copied
+impl<T> Clone for PersistentCidrTree<T> {
+    fn clone(&self) -> PersistentCidrTree<T> {
+        PersistentCidrTree {
+            v4: Arc::clone(&self.v4),
+            v6: Arc::clone(&self.v6),
+            len: self.len,
+        }
+    }
+}
+
+impl<T> Debug for PersistentCidrTree<T> where T: Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<T> Default for PersistentCidrTree<T> {
+    fn default() -> PersistentCidrTree<T> {
+        PersistentCidrTree::new()
+    }
+}
+
+impl<T> CidrTree<T> where T: Clone {
+    // Copies the tree into a PersistentCidrTree whose clones are cheap
+    pub fn snapshot(&self) -> PersistentCidrTree<T> {
+        let mut snapshot = PersistentCidrTree::new();
+        for (cidr, data) in self.iter() {
+            snapshot.insert(cidr, data.clone());
+        }
+        snapshot
+    }
+}
+
+impl<T> PersistentCidrTree<T> {
+    pub fn new() -> PersistentCidrTree<T> {
+        PersistentCidrTree {
+            v4: Arc::new(Node::new()),
+            v6: Arc::new(Node::new()),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Returns true if both trees still share their root nodes, i.e. neither
+    // has been modified since one was cloned from the other
+    pub fn ptr_eq(&self, other: &PersistentCidrTree<T>) -> bool {
+        Arc::ptr_eq(&self.v4, &other.v4) && Arc::ptr_eq(&self.v6, &other.v6)
+    }
+
+    // Returns a vector of all the data that applies to the queried CIDR, from
+    // least to most specific
+    pub fn get<C: Into<Cidr>>(&self, cidr: C) -> Vec<&T> {
+        let cidr = cidr.into();
+        let mut results = Vec::new();
+        let mut node = self.root(&cidr);
+        let mut rest = cidr;
+        loop {
+            if let Some(ref d) = node.data {
+                results.push(d);
+            }
+            if rest.length == 0 {
+                break;
+            }
+            match node.child(rest.msbit()) {
+                Some(child) => node = child,
+                None => break,
+            }
+            rest = rest.next();
+        }
+        results
+    }
+
+    // Returns the data of the most specific stored CIDR covering the query
+    pub fn longest_match<C: Into<Cidr>>(&self, cidr: C) -> Option<&T> {
+        let cidr = cidr.into();
+        let mut node = self.root(&cidr);
+        let mut rest = cidr;
+        let mut longest = node.data.as_ref();
+        while rest.length > 0 {
+            match node.child(rest.msbit()) {
+                Some(child) => node = child,
+                None => break,
+            }
+            longest = node.data.as_ref().or(longest);
+            rest = rest.next();
+        }
+        longest
+    }
+
+    pub fn get_exact<C: Into<Cidr>>(&self, cidr: C) -> Option<&T> {
+        let cidr = cidr.into();
+        let mut node = self.root(&cidr);
+        let mut rest = cidr;
+        while rest.length > 0 {
+            node = node.child(rest.msbit())?;
+            rest = rest.next();
+        }
+        node.data.as_ref()
+    }
+
+    // Iterates over every stored CIDR and its data in the same order as
+    // CidrTree::iter()
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![(Cidr::new(Prefix::V6([0; 16]), 0), &self.v6),
+                        (Cidr::new(Prefix::V4([0; 4]), 0), &self.v4)],
+        }
+    }
+
+    fn root(&self, cidr: &Cidr) -> &Node<T> {
+        match cidr.prefix {
+            Prefix::V4(_) => &self.v4,
+            Prefix::V6(_) => &self.v6,
+        }
+    }
+}
+
+// Changing the tree may copy shared nodes, which needs T: Clone
+impl<T> PersistentCidrTree<T> where T: Clone {
+    // Stores data at the given CIDR and returns the data it replaced, if any.
+    // Nodes shared with other clones are copied before being changed.
+    pub fn insert<C: Into<Cidr>>(&mut self, cidr: C, data: T) -> Option<T> {
+        let cidr = cidr.into();
+        let replaced = Arc::make_mut(self.root_mut(&cidr)).insert(&cidr, data);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    // Removes and returns the data stored at exactly the given CIDR, pruning
+    // branches left empty. Nothing is copied if the CIDR has no data.
+    pub fn remove<C: Into<Cidr>>(&mut self, cidr: C) -> Option<T> {
+        let cidr = cidr.into();
+        self.get_exact(cidr)?;
+        let removed = Arc::make_mut(self.root_mut(&cidr)).remove(&cidr);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    // Copies the entries into a regular, uniquely owned CidrTree
+    pub fn to_tree(&self) -> CidrTree<T> {
+        self.iter().map(|(cidr, data)| (cidr, data.clone())).collect()
+    }
+
+    fn root_mut(&mut self, cidr: &Cidr) -> &mut Arc<Node<T>> {
+        match cidr.prefix {
+            Prefix::V4(_) => &mut self.v4,
+            Prefix::V6(_) => &mut self.v6,
+        }
+    }
+}
+
+// Iterator over the entries of a PersistentCidrTree, created by
+// PersistentCidrTree::iter()
+pub struct Iter<'a, T> where T: 'a {
+    stack: Vec<(Cidr, &'a Node<T>)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Cidr, &'a T);
+
+    fn next(&mut self) -> Option<(Cidr, &'a T)> {
+        while let Some((key, node)) = self.stack.pop() {
+            if let Some(ref child) = node.one {
+                self.stack.push((key.child(1), child));
+            }
+            if let Some(ref child) = node.zero {
+                self.stack.push((key.child(0), child));
+            }
+            if let Some(ref d) = node.data {
+                return Some((key, d));
+            }
+        }
+        None
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            zero: None,
+            one: None,
+            data: None,
+        }
+    }
+
+    fn child(&self, bit: u8) -> Option<&Node<T>> {
+        match bit {
+            0 => self.zero.as_deref(),
+            _ => self.one.as_deref(),
+        }
+    }
+
+    fn is_prunable(&self) -> bool {
+        self.data.is_none() && self.zero.is_none() && self.one.is_none()
+    }
+}
+
+impl<T> Node<T> where T: Clone {
+    fn insert(&mut self, cidr: &Cidr, data: T) -> Option<T> {
+        if cidr.length == 0 {
+            return self.data.replace(data);
+        }
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        let child = child.get_or_insert_with(|| Arc::new(Node::new()));
+        Arc::make_mut(child).insert(&cidr.next(), data)
+    }
+
+    fn remove(&mut self, cidr: &Cidr) -> Option<T> {
+        if cidr.length == 0 {
+            return self.data.take();
+        }
+        let child = match cidr.msbit() {
+            0 => &mut self.zero,
+            _ => &mut self.one,
+        };
+        let removed = match *child {
+            Some(ref mut node) => Arc::make_mut(node).remove(&cidr.next()),
+            None => None,
+        };
+        if child.as_ref().is_some_and(|node| node.is_prunable()) {
+            *child = None;
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_snapshot() {
+    let mut t = CidrTree::<u32>::new();
+    let a = Cidr::from_str("10.0.0.0/8").unwrap();
+    let b = Cidr::from_str("10.1.0.0/16").unwrap();
+    let c = Cidr::from_str("2001:db8::/32").unwrap();
+    t.insert(a, 1);
+    t.insert(b, 2);
+
+    let first = t.snapshot();
+    let mut second = first.clone();
+    assert!(first.ptr_eq(&second));
+
+    second.insert(b, 3);
+    second.insert(c, 4);
+    assert!(!first.ptr_eq(&second));
+    assert!(first.get(Cidr::from_str("10.1.2.3").unwrap()) == vec![&1, &2]);
+    assert!(second.get(Cidr::from_str("10.1.2.3").unwrap()) == vec![&1, &3]);
+    assert!(first.get_exact(c).is_none());
+    assert!(second.longest_match(Cidr::from_str("2001:db8::1").unwrap()) == Some(&4));
+
+    assert!(second.remove(a) == Some(1));
+    assert!(second.remove(a).is_none());
+    assert!(first.len() == 2 && second.len() == 2);
+    assert!(first.to_tree() == t);
+    assert!(second.iter().collect::<Vec<_>>() == vec![(b, &3), (c, &4)]);
+}
+
+#[test]
+fn test_persistent_queries() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    let mut t = PersistentCidrTree::new();
+    t.insert(c("10.0.0.0/8"), 1);
+    t.insert(c("10.1.2.0/24"), 2);
+    let ip: ::std::net::IpAddr = "10.1.2.3".parse().unwrap();
+    assert!(t.longest_match(ip) == Some(&2));
+    assert!(t.longest_match(c("10.1.3.0/24")) == Some(&1));
+    assert!(t.longest_match(c("11.0.0.0/8")).is_none());
+    assert!(t.get(ip) == vec![&1, &2]);
+    assert!(t.get_exact(c("10.1.2.0/24")) == Some(&2) && t.get_exact(ip).is_none());
+    assert!(t.remove(c("10.0.0.0/8")) == Some(1));
+
+    // Read-only use needs no Clone
+    #[derive(Debug)]
+    struct NotClone;
+    let empty = PersistentCidrTree::<NotClone>::default();
+    assert!(empty.longest_match(ip).is_none() && empty.iter().next().is_none());
+    assert!(format!("{:?}", empty.clone()) == "{}");
+}
@@ -1,27 +1,85 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
 use std::net;
 use std::str::FromStr;
-use std::mem::transmute;
+use cidr::Cidr;
+use special;
 
 // Stores an IPv4 prefix or an IPv6 prefix in a byte array.
 // Bytes are stored little-endian; e.g.:
 //   1.2.3.4 -> [4, 3, 2, 1]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Prefix {
     V4([u8; 4]),
     V6([u8; 16]),
 }
 
+// IPv6 addresses may carry a zone ID, as in fe80::1%eth0; it is discarded. Use
+// Prefix::from_str_with_zone() to keep it.
 impl FromStr for Prefix {
     type Err = net::AddrParseError;
     fn from_str(s: &str) -> Result<Prefix, net::AddrParseError> {
-        let v4 = net::Ipv4Addr::from_str(s).and_then(|ip| Ok(Prefix::from_ipv4(ip)));
-        if v4.is_ok() { return v4 };
-        net::Ipv6Addr::from_str(s).and_then(|ip| Ok(Prefix::from_ipv6(ip)))
+        Prefix::from_str_with_zone(s).map(|(prefix, _)| prefix)
+    }
+}
+
+// Prefixes sort numerically within a family, with IPv4 before IPv6
+impl Ord for Prefix {
+    fn cmp(&self, other: &Prefix) -> Ordering {
+        let key = |p: &Prefix| (p.bits(), p.to_int());
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for Prefix {
+    fn partial_cmp(&self, other: &Prefix) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Formats as the IP address, e.g. 1.2.3.4 or 2001:db8::1
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_ip_addr())
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Prefix {
+    type Error = net::AddrParseError;
+
+    fn try_from(s: &'a str) -> Result<Prefix, net::AddrParseError> {
+        Prefix::from_str(s)
+    }
+}
+
+impl TryFrom<String> for Prefix {
+    type Error = net::AddrParseError;
+
+    fn try_from(s: String) -> Result<Prefix, net::AddrParseError> {
+        Prefix::from_str(&s)
     }
 }
 
 impl Prefix {
 
+    // Parses an address, returning its zone ID separately if it has one. Only
+    // IPv6 addresses may have a zone ID, and it must not be empty.
+    pub fn from_str_with_zone(s: &str) -> Result<(Prefix, Option<String>), net::AddrParseError> {
+        if let Some((addr, zone)) = s.split_once('%') {
+            if !zone.is_empty() {
+                if let Ok(ip) = net::Ipv6Addr::from_str(addr) {
+                    return Ok((Prefix::from_ipv6(ip), Some(zone.to_string())));
+                }
+            }
+            // Fails, since '%' is not valid in an address
+            return net::Ipv6Addr::from_str(s).map(|ip| (Prefix::from_ipv6(ip), None));
+        }
+        let v4 = net::Ipv4Addr::from_str(s).map(Prefix::from_ipv4);
+        if let Ok(prefix) = v4 { return Ok((prefix, None)) };
+        net::Ipv6Addr::from_str(s).map(|ip| (Prefix::from_ipv6(ip), None))
+    }
+
     pub fn from_ipv4(ip: net::Ipv4Addr) -> Prefix {
         Prefix::V4(ip.octets()).reverse_bytes()
     }
@@ -30,6 +88,20 @@ impl Prefix {
         Prefix::V6(ip.octets()).reverse_bytes()
     }
 
+    pub fn from_ip(ip: net::IpAddr) -> Prefix {
+        match ip {
+            net::IpAddr::V4(ip) => Prefix::from_ipv4(ip),
+            net::IpAddr::V6(ip) => Prefix::from_ipv6(ip),
+        }
+    }
+
+    pub fn to_ip_addr(self) -> net::IpAddr {
+        match self.reverse_bytes() {
+            Prefix::V4(bytes) => net::IpAddr::from(bytes),
+            Prefix::V6(bytes) => net::IpAddr::from(bytes),
+        }
+    }
+
     pub fn msbit(&self) -> u8 {
         match *self {
             Prefix::V4(bytes) => (bytes[3] & 0x80) >> 7,
@@ -37,38 +109,161 @@ impl Prefix {
         }
     }
 
+    // Address classification, as on a full-length Cidr
+    pub fn is_private(&self) -> bool {
+        self.host().is_private()
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.host().is_loopback()
+    }
+
+    pub fn is_link_local(&self) -> bool {
+        self.host().is_link_local()
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.host().is_multicast()
+    }
+
+    pub fn is_documentation(&self) -> bool {
+        self.host().is_documentation()
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.host().is_global()
+    }
+
+    // Returns the IPv4 address embedded in a 6to4 address (2002::/16), e.g.
+    // 192.0.2.1 for 2002:c000:201::1
+    pub fn six_to_four_ipv4(&self) -> Option<net::Ipv4Addr> {
+        match self.host().within_any(special::SIX_TO_FOUR) {
+            true => Some(net::Ipv4Addr::from((self.to_int() >> 80) as u32)),
+            false => None,
+        }
+    }
+
+    // Returns the public IPv4 address of the client of a Teredo address
+    // (2001::/32), which is stored in the last 32 bits with every bit inverted
+    pub fn teredo_ipv4(&self) -> Option<net::Ipv4Addr> {
+        match self.host().within_any(special::TEREDO) {
+            true => Some(net::Ipv4Addr::from(!(self.to_int() as u32))),
+            false => None,
+        }
+    }
+
+    // Returns the IPv4 address of the Teredo server of a Teredo address
+    pub fn teredo_server_ipv4(&self) -> Option<net::Ipv4Addr> {
+        match self.host().within_any(special::TEREDO) {
+            true => Some(net::Ipv4Addr::from((self.to_int() >> 64) as u32)),
+            false => None,
+        }
+    }
+
+    // Returns the IPv4 address a 6to4 or Teredo address tunnels traffic for;
+    // for Teredo, this is the client's address
+    pub fn embedded_ipv4(&self) -> Option<net::Ipv4Addr> {
+        self.six_to_four_ipv4().or_else(|| self.teredo_ipv4())
+    }
+
+    fn host(&self) -> Cidr {
+        Cidr::new(*self, self.bits())
+    }
+
+    // Returns the number of bits in an address of this family
+    pub fn bits(&self) -> u8 {
+        match *self {
+            Prefix::V4(_) => 32,
+            Prefix::V6(_) => 128,
+        }
+    }
+
+    // Returns the address as an integer; IPv4 addresses occupy the low 32 bits
+    pub(crate) fn to_int(self) -> u128 {
+        match self {
+            Prefix::V4(bytes) => u32::from_le_bytes(bytes) as u128,
+            Prefix::V6(bytes) => u128::from_le_bytes(bytes),
+        }
+    }
+
+    // Returns a prefix of the same address family holding the given integer,
+    // the inverse of to_int()
+    pub(crate) fn with_int(self, n: u128) -> Prefix {
+        match self {
+            Prefix::V4(_) => Prefix::V4((n as u32).to_le_bytes()),
+            Prefix::V6(_) => Prefix::V6(n.to_le_bytes()),
+        }
+    }
+
+    // Returns an all-zero prefix of the same address family
+    pub fn zeroed(&self) -> Prefix {
+        match *self {
+            Prefix::V4(_) => Prefix::V4([0; 4]),
+            Prefix::V6(_) => Prefix::V6([0; 16]),
+        }
+    }
+
+    // Returns bit i, counting from the most significant bit. Panics if i is
+    // not less than bits().
+    pub fn bit(&self, i: usize) -> u8 {
+        assert!(i < self.bits() as usize, "bit index {} out of range for a {}-bit prefix", i, self.bits());
+        let (bytes, top): (&[u8], usize) = match *self {
+            Prefix::V4(ref bytes) => (bytes, 3),
+            Prefix::V6(ref bytes) => (bytes, 15),
+        };
+        (bytes[top - i / 8] >> (7 - i % 8)) & 1
+    }
+
+    // Returns the number of leading bits the prefixes share. Panics if they are
+    // of different address families.
+    pub fn common_prefix_len(&self, other: &Prefix) -> u8 {
+        let bits = self.bits();
+        assert!(bits == other.bits(), "prefixes are of different address families");
+        let differing = self.to_int() ^ other.to_int();
+        (differing.leading_zeros() as u8 - (128 - bits)).min(bits)
+    }
+
+    // Sets bit i, counting from the most significant bit, to v. Panics if i is
+    // not less than bits().
+    pub fn set_bit(&mut self, i: usize, v: u8) {
+        assert!(i < self.bits() as usize, "bit index {} out of range for a {}-bit prefix", i, self.bits());
+        let (bytes, top): (&mut [u8], usize) = match *self {
+            Prefix::V4(ref mut bytes) => (bytes, 3),
+            Prefix::V6(ref mut bytes) => (bytes, 15),
+        };
+        let mask = 0x80 >> (i % 8);
+        if v == 0 {
+            bytes[top - i / 8] &= !mask;
+        } else {
+            bytes[top - i / 8] |= mask;
+        }
+    }
+
+    // Shifts the address left by n bits; shifting by the full width of the
+    // address or more yields zero
     pub fn shift_left(&self, n: usize) -> Prefix {
+        let n = u32::try_from(n).unwrap_or(u32::MAX);
         match *self {
             Prefix::V4(bytes) => {
-                let shifted = unsafe {
-                    let word = transmute::<[u8; 4], u32>(bytes);
-                    transmute::<u32, [u8; 4]>(word << n)
-                };
-                Prefix::V4(shifted)
+                let word = u32::from_le_bytes(bytes);
+                Prefix::V4(word.checked_shl(n).unwrap_or(0).to_le_bytes())
             },
             Prefix::V6(bytes) => {
-                let shifted = unsafe {
-                    let words = transmute::<[u8; 16], [u64; 2]>(bytes);
-                    let shifted_words = [words[0] << n, (words[1] << n) | (words[0] >> (64 - n))];
-                    transmute::<[u64; 2], [u8; 16]>(shifted_words)
-                };
-                Prefix::V6(shifted)
+                let word = u128::from_le_bytes(bytes);
+                Prefix::V6(word.checked_shl(n).unwrap_or(0).to_le_bytes())
             }
         }
     }
 
     fn reverse_bytes(&self) -> Prefix {
         match *self {
-            Prefix::V4(bytes) => unsafe {
-                let word = transmute::<[u8; 4], u32>(bytes);
-                let swapped_bytes = transmute::<u32, [u8; 4]>(word.swap_bytes());
-                Prefix::V4(swapped_bytes)
+            Prefix::V4(mut bytes) => {
+                bytes.reverse();
+                Prefix::V4(bytes)
             },
-            Prefix::V6(bytes) => unsafe {
-                let words = transmute::<[u8; 16], [u64; 2]>(bytes);
-                let swapped_words = [words[1].swap_bytes(), words[0].swap_bytes()];
-                let swapped_bytes = transmute::<[u64; 2], [u8; 16]>(swapped_words);
-                Prefix::V6(swapped_bytes)
+            Prefix::V6(mut bytes) => {
+                bytes.reverse();
+                Prefix::V6(bytes)
             }
         }
     }
@@ -83,12 +278,160 @@ fn test_from_str() {
     assert!(p == Prefix::V6([4, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
 }
 
+#[test]
+fn test_from_str_zone() {
+    let p = Prefix::from_str("fe80::1%eth0").unwrap();
+    assert!(p == Prefix::from_str("fe80::1").unwrap());
+    assert!(Prefix::from_str_with_zone("fe80::1%eth0").unwrap() == (p, Some("eth0".to_string())));
+    assert!(Prefix::from_str_with_zone("fe80::1%3").unwrap().1 == Some("3".to_string()));
+    assert!(Prefix::from_str_with_zone("fe80::1").unwrap() == (p, None));
+    assert!(Prefix::from_str("fe80::1%").is_err());
+    assert!(Prefix::from_str("1.2.3.4%eth0").is_err());
+    assert!(Prefix::from_str("%eth0").is_err());
+}
+
+#[test]
+fn test_try_from() {
+    assert!(Prefix::try_from("1.2.3.4").unwrap() == Prefix::V4([4, 3, 2, 1]));
+    assert!(Prefix::try_from("::1".to_string()).unwrap() == Prefix::from_str("::1").unwrap());
+    assert!(Prefix::try_from("1.2.3").is_err());
+}
+
 #[test]
 fn test_from_msbit() {
     assert!(0 == Prefix::from_str("1.0.0.0").unwrap().msbit());
     assert!(1 == Prefix::from_str("128.0.0.0").unwrap().msbit());
 }
 
+#[test]
+fn test_to_ip_addr() {
+    assert!(Prefix::from_str("1.2.3.4").unwrap().to_ip_addr() == "1.2.3.4".parse::<net::IpAddr>().unwrap());
+    assert!(Prefix::from_str("2001:db8::1").unwrap().to_ip_addr() ==
+            "2001:db8::1".parse::<net::IpAddr>().unwrap());
+}
+
+#[test]
+fn test_ord() {
+    let p = |s: &str| Prefix::from_str(s).unwrap();
+    assert!(p("1.2.3.4") < p("1.2.3.5"));
+    assert!(p("9.0.0.0") < p("10.0.0.0"));
+    assert!(p("255.255.255.255") < p("::"));
+    assert!(p("::1") < p("2001:db8::"));
+
+    let mut v = vec![p("::1"), p("10.0.0.1"), p("2.0.0.0"), p("::")];
+    v.sort();
+    assert!(v == vec![p("2.0.0.0"), p("10.0.0.1"), p("::"), p("::1")]);
+
+    let set = v.iter().cloned().collect::<::std::collections::HashSet<_>>();
+    assert!(set.contains(&p("10.0.0.1")));
+}
+
+#[test]
+fn test_display() {
+    assert!(Prefix::from_str("1.2.3.4").unwrap().to_string() == "1.2.3.4");
+    assert!(Prefix::from_str("2001:0db8::0001").unwrap().to_string() == "2001:db8::1");
+}
+
+#[test]
+fn test_int() {
+    let p = Prefix::from_str("1.2.3.4").unwrap();
+    assert!(p.to_int() == 0x01020304);
+
+    let p = Prefix::from_str("2001:db8::1").unwrap();
+    assert!(p.to_int() == 0x20010db8000000000000000000000001);
+    assert!(p.zeroed().with_int(p.to_int()) == p);
+    assert!(Prefix::V4([0; 4]).with_int(0x01020304) == Prefix::from_str("1.2.3.4").unwrap());
+}
+
+#[test]
+fn test_classification() {
+    assert!(Prefix::from_str("10.1.2.3").unwrap().is_private());
+    assert!(Prefix::from_str("::1").unwrap().is_loopback());
+    assert!(Prefix::from_str("1.1.1.1").unwrap().is_global());
+    assert!(!Prefix::from_str("192.168.0.1").unwrap().is_global());
+}
+
+#[test]
+fn test_bit() {
+    let p = Prefix::from_str("128.0.0.1").unwrap();
+    assert!(p.bit(0) == 1);
+    assert!(p.bit(1) == 0);
+    assert!(p.bit(31) == 1);
+
+    let p = Prefix::from_str("::1").unwrap();
+    assert!(p.bit(0) == 0);
+    assert!(p.bit(127) == 1);
+}
+
+#[test]
+#[should_panic(expected = "bit index 32 out of range for a 32-bit prefix")]
+fn test_bit_out_of_range() {
+    Prefix::from_str("255.255.255.255").unwrap().bit(32);
+}
+
+#[test]
+#[should_panic(expected = "bit index 128 out of range for a 128-bit prefix")]
+fn test_bit_out_of_range_v6() {
+    Prefix::from_str("::1").unwrap().bit(128);
+}
+
+#[test]
+fn test_common_prefix_len() {
+    let p = |s: &str| Prefix::from_str(s).unwrap();
+    assert!(p("10.1.2.0").common_prefix_len(&p("10.1.3.0")) == 23);
+    assert!(p("10.1.2.3").common_prefix_len(&p("10.1.2.3")) == 32);
+    assert!(p("0.0.0.0").common_prefix_len(&p("128.0.0.0")) == 0);
+    assert!(p("2001:db8::").common_prefix_len(&p("2001:db9::")) == 31);
+    assert!(p("::1").common_prefix_len(&p("::1")) == 128);
+}
+
+#[test]
+#[should_panic]
+fn test_common_prefix_len_families() {
+    Prefix::from_str("::").unwrap().common_prefix_len(&Prefix::from_str("0.0.0.0").unwrap());
+}
+
+#[test]
+fn test_embedded_ipv4() {
+    let p = |s: &str| Prefix::from_str(s).unwrap();
+    let v4 = |s: &str| s.parse::<net::Ipv4Addr>().unwrap();
+    assert!(p("2002:c000:201::1").six_to_four_ipv4() == Some(v4("192.0.2.1")));
+    assert!(p("2002:c000:201::1").embedded_ipv4() == Some(v4("192.0.2.1")));
+    assert!(p("2002:c000:201::1").teredo_ipv4().is_none());
+
+    // Example from RFC 4380 section 4
+    let teredo = p("2001:0:4136:e378:8000:63bf:3fff:fdd2");
+    assert!(teredo.teredo_ipv4() == Some(v4("192.0.2.45")));
+    assert!(teredo.teredo_server_ipv4() == Some(v4("65.54.227.120")));
+    assert!(teredo.embedded_ipv4() == Some(v4("192.0.2.45")));
+    assert!(teredo.six_to_four_ipv4().is_none());
+
+    assert!(p("2001:db8::1").embedded_ipv4().is_none());
+    assert!(p("192.0.2.1").embedded_ipv4().is_none());
+}
+
+#[test]
+fn test_set_bit() {
+    let mut p = Prefix::V4([0, 0, 0, 0]);
+    p.set_bit(0, 1);
+    p.set_bit(31, 1);
+    assert!(p == Prefix::V4([1, 0, 0, 128]));
+    p.set_bit(0, 0);
+    assert!(p == Prefix::V4([1, 0, 0, 0]));
+
+    let mut p = Prefix::from_str("::").unwrap();
+    p.set_bit(8, 1);
+    assert!(p == Prefix::from_str("80::").unwrap());
+    p.set_bit(127, 1);
+    assert!(p == Prefix::from_str("80::1").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "bit index 32 out of range for a 32-bit prefix")]
+fn test_set_bit_out_of_range() {
+    Prefix::V4([0, 0, 0, 0]).set_bit(32, 1);
+}
+
 #[test]
 fn test_shift_left_v4() {
     assert!(Prefix::V4([0, 0, 0, 1]).shift_left(1) ==
@@ -113,6 +456,32 @@ fn test_shift_left_v6() {
             Prefix::V6([0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
 }
 
+#[test]
+fn test_shift_left_roundtrip() {
+    let p = Prefix::from_str("1.2.3.4").unwrap();
+    assert!(p.shift_left(8) == Prefix::from_str("2.3.4.0").unwrap());
+
+    let p = Prefix::from_str("2001:db8::1").unwrap();
+    assert!(p.shift_left(16) == Prefix::from_str("db8::1:0").unwrap());
+    assert!(p.shift_left(64).to_ip_addr() == "0:0:0:1::".parse::<net::IpAddr>().unwrap());
+}
+
+#[test]
+fn test_shift_left_any_amount() {
+    let p = Prefix::from_str("255.255.255.255").unwrap();
+    assert!(p.shift_left(0) == p);
+    assert!(p.shift_left(31) == Prefix::from_str("128.0.0.0").unwrap());
+    assert!(p.shift_left(32) == p.zeroed());
+    assert!(p.shift_left(usize::MAX) == p.zeroed());
+
+    let p = Prefix::from_str("ffff::1").unwrap();
+    assert!(p.shift_left(0) == p);
+    assert!(p.shift_left(70) == Prefix::from_str("0:0:0:40::").unwrap());
+    assert!(p.shift_left(127) == Prefix::from_str("8000::").unwrap());
+    assert!(p.shift_left(128) == p.zeroed());
+    assert!(p.shift_left(200) == p.zeroed());
+}
+
 #[test]
 fn test_reverse_bytes_v4() {
     assert!(Prefix::V4([1, 2, 3, 4]).reverse_bytes() ==
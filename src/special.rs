@@ -0,0 +1,168 @@
+use cidr::Cidr;
+use cidr_tree::CidrTree;
+use prefix::Prefix;
+
+// An entry of the IANA IPv4 or IPv6 special-purpose address registry
+// (RFC 6890 and its updates)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecialPurpose {
+    pub cidr: Cidr,
+    pub name: &'static str,
+    // The RFC defining the range
+    pub rfc: &'static str,
+    // Whether the registry marks addresses in the range as globally reachable
+    pub globally_reachable: bool,
+}
+
+const fn v4(addr: u32, length: u8) -> Cidr {
+    Cidr {
+        prefix: Prefix::V4(addr.to_le_bytes()),
+        length,
+    }
+}
+
+const fn v6(addr: u128, length: u8) -> Cidr {
+    Cidr {
+        prefix: Prefix::V6(addr.to_le_bytes()),
+        length,
+    }
+}
+
+const fn entry(cidr: Cidr, name: &'static str, rfc: &'static str, globally_reachable: bool)
+    -> SpecialPurpose
+{
+    SpecialPurpose {
+        cidr,
+        name,
+        rfc,
+        globally_reachable,
+    }
+}
+
+// The IANA IPv4 and IPv6 special-purpose address registries, in address
+// order. Where the registry gives no reachability ("N/A"), the entry is marked
+// not globally reachable.
+pub const IPV4_SPECIAL_PURPOSE: &[SpecialPurpose] = &[
+    entry(v4(0x00000000, 8), "This network", "RFC 791", false),
+    entry(v4(0x0a000000, 8), "Private-Use", "RFC 1918", false),
+    entry(v4(0x64400000, 10), "Shared Address Space", "RFC 6598", false),
+    entry(v4(0x7f000000, 8), "Loopback", "RFC 1122", false),
+    entry(v4(0xa9fe0000, 16), "Link Local", "RFC 3927", false),
+    entry(v4(0xac100000, 12), "Private-Use", "RFC 1918", false),
+    entry(v4(0xc0000000, 24), "IETF Protocol Assignments", "RFC 6890", false),
+    entry(v4(0xc0000000, 29), "IPv4 Service Continuity Prefix", "RFC 7335", false),
+    entry(v4(0xc0000008, 32), "IPv4 dummy address", "RFC 7600", false),
+    entry(v4(0xc0000009, 32), "Port Control Protocol Anycast", "RFC 7723", true),
+    entry(v4(0xc000000a, 32), "Traversal Using Relays around NAT Anycast", "RFC 8155", true),
+    entry(v4(0xc00000aa, 32), "NAT64/DNS64 Discovery", "RFC 8880", false),
+    entry(v4(0xc00000ab, 32), "NAT64/DNS64 Discovery", "RFC 8880", false),
+    entry(v4(0xc0000200, 24), "Documentation (TEST-NET-1)", "RFC 5737", false),
+    entry(v4(0xc01fc400, 24), "AS112-v4", "RFC 7535", true),
+    entry(v4(0xc034c100, 24), "AMT", "RFC 7450", true),
+    entry(v4(0xc0586300, 24), "Deprecated (6to4 Relay Anycast)", "RFC 7526", false),
+    entry(v4(0xc0a80000, 16), "Private-Use", "RFC 1918", false),
+    entry(v4(0xc0af3000, 24), "Direct Delegation AS112 Service", "RFC 7534", true),
+    entry(v4(0xc6120000, 15), "Benchmarking", "RFC 2544", false),
+    entry(v4(0xc6336400, 24), "Documentation (TEST-NET-2)", "RFC 5737", false),
+    entry(v4(0xcb007100, 24), "Documentation (TEST-NET-3)", "RFC 5737", false),
+    entry(v4(0xf0000000, 4), "Reserved", "RFC 1112", false),
+    entry(v4(0xffffffff, 32), "Limited Broadcast", "RFC 919", false),
+];
+
+pub const IPV6_SPECIAL_PURPOSE: &[SpecialPurpose] = &[
+    entry(v6(0, 128), "Unspecified Address", "RFC 4291", false),
+    entry(v6(1, 128), "Loopback Address", "RFC 4291", false),
+    entry(v6(0xffff << 32, 96), "IPv4-mapped Address", "RFC 4291", false),
+    entry(v6(0x0064ff9b << 96, 96), "IPv4-IPv6 Translation", "RFC 6052", true),
+    entry(v6(0x0064ff9b0001 << 80, 48), "IPv4-IPv6 Translation", "RFC 8215", false),
+    entry(v6(0x0100 << 112, 64), "Discard-Only Address Block", "RFC 6666", false),
+    entry(v6(0x2001 << 112, 23), "IETF Protocol Assignments", "RFC 2928", false),
+    entry(v6(0x20010000 << 96, 32), "TEREDO", "RFC 4380", false),
+    entry(v6(0x20010001 << 96 | 1, 128), "Port Control Protocol Anycast", "RFC 7723", true),
+    entry(v6(0x20010001 << 96 | 2, 128), "Traversal Using Relays around NAT Anycast", "RFC 8155", true),
+    entry(v6(0x20010001 << 96 | 3, 128), "DNS-SD Service Registration Protocol Anycast", "RFC 9665", true),
+    entry(v6(0x20010002 << 96, 48), "Benchmarking", "RFC 5180", false),
+    entry(v6(0x20010003 << 96, 32), "AMT", "RFC 7450", true),
+    entry(v6(0x200100040112 << 80, 48), "AS112-v6", "RFC 7535", true),
+    entry(v6(0x20010010 << 96, 28), "Deprecated (previously ORCHID)", "RFC 4843", false),
+    entry(v6(0x20010020 << 96, 28), "ORCHIDv2", "RFC 7343", true),
+    entry(v6(0x20010030 << 96, 28), "Drone Remote ID Protocol Entity Tags (DETs) Prefix", "RFC 9374", true),
+    entry(v6(0x20010db8 << 96, 32), "Documentation", "RFC 3849", false),
+    entry(v6(0x2002 << 112, 16), "6to4", "RFC 3056", true),
+    entry(v6(0x2620004f8000 << 80, 48), "Direct Delegation AS112 Service", "RFC 7534", true),
+    entry(v6(0x3fff << 112, 20), "Documentation", "RFC 9637", false),
+    entry(v6(0x5f00 << 112, 16), "Segment Routing (SRv6) SIDs", "RFC 9602", false),
+    entry(v6(0xfc00 << 112, 7), "Unique-Local", "RFC 4193", false),
+    entry(v6(0xfe80 << 112, 10), "Link-Local Unicast", "RFC 4291", false),
+];
+
+// Commonly used groups of the ranges above, plus multicast, which has its own
+// registries
+
+// RFC 1918 networks and IPv6 unique local addresses
+pub const PRIVATE: &[Cidr] = &[
+    v4(0x0a000000, 8), v4(0xac100000, 12), v4(0xc0a80000, 16), v6(0xfc00 << 112, 7),
+];
+
+// Carrier-grade NAT space (RFC 6598)
+pub const SHARED_ADDRESS_SPACE: &[Cidr] = &[v4(0x64400000, 10)];
+
+pub const LOOPBACK: &[Cidr] = &[v4(0x7f000000, 8), v6(1, 128)];
+
+pub const LINK_LOCAL: &[Cidr] = &[v4(0xa9fe0000, 16), v6(0xfe80 << 112, 10)];
+
+pub const DOCUMENTATION: &[Cidr] = &[
+    v4(0xc0000200, 24), v4(0xc6336400, 24), v4(0xcb007100, 24),
+    v6(0x20010db8 << 96, 32), v6(0x3fff << 112, 20),
+];
+
+pub const BENCHMARKING: &[Cidr] = &[v4(0xc6120000, 15), v6(0x20010002 << 96, 48)];
+
+pub const MULTICAST: &[Cidr] = &[v4(0xe0000000, 4), v6(0xff00 << 112, 8)];
+
+// IPv6 transition mechanisms that embed an IPv4 address: 6to4 (RFC 3056)
+// and Teredo (RFC 4380)
+pub const SIX_TO_FOUR: &[Cidr] = &[v6(0x2002 << 112, 16)];
+
+pub const TEREDO: &[Cidr] = &[v6(0x20010000 << 96, 32)];
+
+// Builds a tree of both special-purpose registries, for classifying addresses
+// by their most specific registry entry
+pub fn special_purpose_tree() -> CidrTree<SpecialPurpose> {
+    IPV4_SPECIAL_PURPOSE.iter().chain(IPV6_SPECIAL_PURPOSE)
+        .map(|&entry| (entry.cidr, entry))
+        .collect()
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[test]
+fn test_tables() {
+    let c = |s: &str| Cidr::from_str(s).unwrap();
+    assert!(PRIVATE[1] == c("172.16.0.0/12") && PRIVATE[3] == c("fc00::/7"));
+    assert!(IPV4_SPECIAL_PURPOSE[2].cidr == c("100.64.0.0/10"));
+    assert!(IPV6_SPECIAL_PURPOSE[7].cidr == TEREDO[0]);
+    for table in [IPV4_SPECIAL_PURPOSE, IPV6_SPECIAL_PURPOSE] {
+        for pair in table.windows(2) {
+            assert!(pair[0].cidr < pair[1].cidr);
+        }
+        assert!(table.iter().all(|entry| entry.cidr.is_canonical()));
+    }
+
+    let tree = special_purpose_tree();
+    assert!(tree.len() == IPV4_SPECIAL_PURPOSE.len() + IPV6_SPECIAL_PURPOSE.len());
+    let lookup = |s: &str| tree.longest_match(&c(s)).map(|entry| entry.name);
+    assert!(lookup("100.100.1.1") == Some("Shared Address Space"));
+    assert!(lookup("2001:2::1/128") == Some("Benchmarking"));
+    assert!(lookup("2001:100::1/128") == Some("IETF Protocol Assignments"));
+    assert!(lookup("192.0.0.9") == Some("Port Control Protocol Anycast"));
+    assert!(lookup("192.88.99.1") == Some("Deprecated (6to4 Relay Anycast)"));
+
+    // Entries nested in a non-global block carry their own reachability
+    let nested = tree.longest_match(&c("2001:20::1")).unwrap();
+    assert!(nested.name == "ORCHIDv2" && nested.globally_reachable);
+    let teredo = tree.longest_match(&c("2001:0:4136:e378::1")).unwrap();
+    assert!(teredo.name == "TEREDO" && !teredo.globally_reachable);
+    assert!(lookup("8.8.8.8").is_none() && lookup("2001:4860::8888/128").is_none());
+}